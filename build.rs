@@ -7,8 +7,10 @@ fn main() {
                 "proto/message.proto",
                 "proto/peer_exchange.proto",
                 "proto/light_push.proto",
+                "proto/light_push_v3.proto",
                 "proto/filter.proto",
                 "proto/metadata.proto",
+                "proto/store.proto",
             ],
             &["proto/"],
         )
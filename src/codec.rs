@@ -0,0 +1,242 @@
+//! Generic protobuf codec shared by the request-response protocols
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{request_response, StreamProtocol};
+use prost::Message;
+use std::{io, marker::PhantomData};
+
+/// Longest encoding of a 64-bit varint
+const MAX_VARINT_LENGTH: usize = 10;
+
+/// How a message is delimited on a stream, with the max size accepted when reading
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Framing {
+    /// Preceded by its length as a varint
+    LengthPrefixed(usize),
+    /// Spans until the writer closes the stream
+    UntilEof(usize),
+}
+
+impl Framing {
+    async fn read<M, T>(self, io: &mut T) -> io::Result<M>
+    where
+        M: Message + Default,
+        T: AsyncRead + Unpin + Send,
+    {
+        let frame = match self {
+            Self::LengthPrefixed(max_size) => read_length_prefixed(io, max_size).await?,
+            Self::UntilEof(max_size) => read_to_end(io, max_size).await?,
+        };
+        Ok(M::decode(&frame[..])?)
+    }
+
+    async fn write<M, T>(self, io: &mut T, message: M) -> io::Result<()>
+    where
+        M: Message,
+        T: AsyncWrite + Unpin + Send,
+    {
+        let buf = match self {
+            Self::LengthPrefixed(_) => message.encode_length_delimited_to_vec(),
+            Self::UntilEof(_) => message.encode_to_vec(),
+        };
+        io.write_all(&buf).await
+    }
+}
+
+/// Request-response codec for protobuf requests and responses
+pub struct ProtoCodec<Req, Resp> {
+    request: Framing,
+    response: Framing,
+    messages: PhantomData<fn() -> (Req, Resp)>,
+}
+
+impl<Req, Resp> ProtoCodec<Req, Resp> {
+    pub(crate) fn new(request: Framing, response: Framing) -> Self {
+        Self {
+            request,
+            response,
+            messages: PhantomData,
+        }
+    }
+}
+
+impl<Req, Resp> Clone for ProtoCodec<Req, Resp> {
+    fn clone(&self) -> Self {
+        Self::new(self.request, self.response)
+    }
+}
+
+#[async_trait]
+impl<Req, Resp> request_response::Codec for ProtoCodec<Req, Resp>
+where
+    Req: Message + Default + Send + 'static,
+    Resp: Message + Default + Send + 'static,
+{
+    type Protocol = StreamProtocol;
+    type Request = Req;
+    type Response = Resp;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Req>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.request.read(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Resp>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.response.read(io).await
+    }
+
+    async fn write_request<T>(&mut self, _: &Self::Protocol, io: &mut T, req: Req) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        self.request.write(io, req).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        resp: Resp,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        self.response.write(io, resp).await
+    }
+}
+
+/// Read a varint length prefixed frame, rejecting frames longer than `max_size`
+/// before allocating for them
+async fn read_length_prefixed<T>(io: &mut T, max_size: usize) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let length = read_varint(io).await?;
+    if length > max_size as u64 {
+        return Err(oversize(length, max_size));
+    }
+    let mut frame = vec![0; length as usize];
+    io.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+/// Read until the end of the stream, rejecting streams longer than `max_size`
+async fn read_to_end<T>(io: &mut T, max_size: usize) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut frame = Vec::new();
+    io.take(max_size as u64 + 1).read_to_end(&mut frame).await?;
+    if frame.len() > max_size {
+        return Err(oversize(frame.len() as u64, max_size));
+    }
+    Ok(frame)
+}
+
+async fn read_varint<T>(io: &mut T) -> io::Result<u64>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut value = 0u64;
+    for index in 0..MAX_VARINT_LENGTH {
+        let mut byte = [0u8];
+        io.read_exact(&mut byte).await?;
+        value |= u64::from(byte[0] & 0x7f) << (7 * index);
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Length prefix overflows a varint",
+    ))
+}
+
+fn oversize(length: u64, max_size: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Frame of {length} bytes exceeds the maximum of {max_size} bytes"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, io::Cursor};
+
+    use super::*;
+    use crate::WakuMessage;
+
+    const MAX_SIZE: usize = 64;
+
+    fn message() -> WakuMessage {
+        WakuMessage {
+            payload: b"payload".to_vec(),
+            content_topic: "/toychat/2/huilong/proto".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn read(framing: Framing, bytes: Vec<u8>) -> (io::Result<WakuMessage>, u64) {
+        let mut io = Cursor::new(bytes);
+        let result = block_on(framing.read(&mut io));
+        (result, io.position())
+    }
+
+    #[test]
+    fn reads_what_it_writes() {
+        for framing in [
+            Framing::LengthPrefixed(MAX_SIZE),
+            Framing::UntilEof(MAX_SIZE),
+        ] {
+            let mut io = Cursor::new(Vec::new());
+            block_on(framing.write(&mut io, message())).unwrap();
+            let (result, _) = read(framing, io.into_inner());
+            assert_eq!(result.unwrap(), message());
+        }
+    }
+
+    #[test]
+    fn rejects_oversize_frame_before_reading_it() {
+        // A length of 65 in two varint bytes, followed by the frame
+        let mut bytes = vec![0xc1, 0x00];
+        bytes.extend([0; MAX_SIZE + 1]);
+        let (result, position) = read(Framing::LengthPrefixed(MAX_SIZE), bytes);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(position, 2);
+    }
+
+    #[test]
+    fn rejects_oversize_stream() {
+        let (result, _) = read(Framing::UntilEof(MAX_SIZE), vec![0; MAX_SIZE + 1]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_varint_overflow() {
+        let (result, position) = read(
+            Framing::LengthPrefixed(MAX_SIZE),
+            vec![0xff; MAX_VARINT_LENGTH + 1],
+        );
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(position, MAX_VARINT_LENGTH as u64);
+    }
+
+    #[test]
+    fn rejects_truncated_varint() {
+        let (result, _) = read(Framing::LengthPrefixed(MAX_SIZE), vec![0x80]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn rejects_truncated_frame() {
+        let mut bytes = message().encode_length_delimited_to_vec();
+        bytes.pop();
+        let (result, _) = read(Framing::LengthPrefixed(MAX_SIZE), bytes);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,256 @@
+//! Light push deliveries, retried with other service nodes until they succeed or give up
+use futures::{future::BoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use libp2p::{request_response::OutboundRequestId, PeerId};
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    handle::Reply, light_push::PushVersion, Error, MessageHash, PubsubTopic, PushOutcome,
+    WakuMessage,
+};
+
+/// How failed light pushes get retried
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts per message, including the first one
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubling with every further one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// A single attempt per message
+    pub fn no_retries() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Wait before the retry following the given number of attempts
+    fn backoff(&self, attempts: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A message being delivered via light push
+pub(crate) struct Delivery {
+    pub pubsub_topic: PubsubTopic,
+    pub message: WakuMessage,
+    pub hash: MessageHash,
+    /// Service nodes the message failed to be pushed to
+    pub tried: Vec<PeerId>,
+    pub attempts: u32,
+    /// Caller waiting for the final outcome, if any
+    pub reply: Option<Reply<PushOutcome>>,
+}
+
+/// Deliveries either in flight or waiting to be retried
+#[derive(Default)]
+pub(crate) struct Deliveries {
+    /// By protocol version, request ids being per version
+    in_flight: HashMap<(PushVersion, OutboundRequestId), (PeerId, Delivery)>,
+    /// Deliveries waiting to be retried, along with the service node last tried
+    backoffs: FuturesUnordered<BoxFuture<'static, (PeerId, Delivery)>>,
+}
+
+impl Deliveries {
+    pub fn sent(
+        &mut self,
+        version: PushVersion,
+        request_id: OutboundRequestId,
+        peer: PeerId,
+        delivery: Delivery,
+    ) {
+        self.in_flight
+            .insert((version, request_id), (peer, delivery));
+    }
+
+    pub fn contains(&self, version: PushVersion, request_id: &OutboundRequestId) -> bool {
+        self.in_flight.contains_key(&(version, *request_id))
+    }
+
+    /// The delivery a request is an attempt of, and the service node it went to
+    pub fn take(
+        &mut self,
+        version: PushVersion,
+        request_id: &OutboundRequestId,
+    ) -> Option<(PeerId, Delivery)> {
+        self.in_flight.remove(&(version, *request_id))
+    }
+
+    /// Attempts in flight: their version, request id, service node and attempt count
+    #[cfg(test)]
+    pub fn in_flight(&self) -> Vec<(PushVersion, OutboundRequestId, PeerId, u32)> {
+        self.in_flight
+            .iter()
+            .map(|((version, request_id), (peer, delivery))| {
+                (*version, *request_id, *peer, delivery.attempts)
+            })
+            .collect()
+    }
+
+    /// Decide on a failed attempt, giving the delivery back unless it is to be retried
+    pub fn failed(
+        &mut self,
+        policy: &RetryPolicy,
+        peer: PeerId,
+        mut delivery: Delivery,
+        error: &Error,
+    ) -> Option<Delivery> {
+        if !is_retryable(error) || delivery.attempts >= policy.max_attempts {
+            return Some(delivery);
+        }
+        delivery.tried.push(peer);
+        let backoff = policy.backoff(delivery.attempts);
+        self.backoffs.push(
+            async move {
+                tokio::time::sleep(backoff).await;
+                (peer, delivery)
+            }
+            .boxed(),
+        );
+        None
+    }
+
+    /// Wait for the next delivery due to be retried, along with the service node last tried
+    pub async fn next_retry(&mut self) -> (PeerId, Delivery) {
+        match self.backoffs.next().await {
+            Some(retry) => retry,
+            None => futures::future::pending().await,
+        }
+    }
+}
+
+/// Whether another attempt, possibly with another service node, may succeed
+fn is_retryable(error: &Error) -> bool {
+    match error {
+        Error::Outbound(_) | Error::MissingResponse | Error::RequestIdMismatch { .. } => true,
+        Error::Push(error) => error.is_retryable(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::request_response::OutboundFailure;
+
+    use super::*;
+    use crate::PushError;
+
+    fn policy(max_attempts: u32, initial_backoff: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff,
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+
+    fn delivery(attempts: u32) -> Delivery {
+        Delivery {
+            pubsub_topic: PubsubTopic::new(1, 0),
+            message: WakuMessage::default(),
+            hash: [0; 32],
+            tried: vec![],
+            attempts,
+            reply: None,
+        }
+    }
+
+    #[test]
+    fn doubles_backoff_up_to_the_max() {
+        let policy = policy(3, Duration::from_secs(1));
+        let backoffs: Vec<u64> = (1..=6)
+            .map(|attempts| policy.backoff(attempts).as_secs())
+            .collect();
+        assert_eq!(backoffs, vec![1, 2, 4, 8, 10, 10]);
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn retries_failures_another_attempt_may_overcome() {
+        assert!(is_retryable(&Error::Outbound(OutboundFailure::Timeout)));
+        assert!(is_retryable(&Error::MissingResponse));
+        assert!(is_retryable(&Error::RequestIdMismatch {
+            expected: "a".to_string(),
+            actual: "b".to_string(),
+        }));
+        assert!(is_retryable(&Error::Push(
+            PushError::NoPeers(String::new())
+        )));
+        assert!(is_retryable(&Error::Push(PushError::TooManyRequests(
+            String::new()
+        ))));
+        assert!(!is_retryable(&Error::Push(PushError::Rejected(
+            String::new()
+        ))));
+        assert!(!is_retryable(&Error::Push(PushError::PayloadTooLarge(
+            String::new()
+        ))));
+        assert!(!is_retryable(&Error::NoShards));
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut deliveries = Deliveries::default();
+        let policy = policy(3, Duration::ZERO);
+        let error = Error::MissingResponse;
+        assert!(deliveries
+            .failed(&policy, PeerId::random(), delivery(2), &error)
+            .is_none());
+        let given_up = deliveries
+            .failed(&policy, PeerId::random(), delivery(3), &error)
+            .unwrap();
+        assert!(given_up.tried.is_empty());
+    }
+
+    #[test]
+    fn gives_up_on_refused_messages() {
+        let mut deliveries = Deliveries::default();
+        let error = Error::Push(PushError::Rejected(String::new()));
+        let given_up = deliveries
+            .failed(
+                &policy(3, Duration::ZERO),
+                PeerId::random(),
+                delivery(1),
+                &error,
+            )
+            .unwrap();
+        assert_eq!(given_up.attempts, 1);
+        assert!(given_up.tried.is_empty());
+    }
+
+    #[tokio::test]
+    async fn retries_with_the_failed_service_node_tried() {
+        let mut deliveries = Deliveries::default();
+        let policy = policy(3, Duration::ZERO);
+        let (first, second) = (PeerId::random(), PeerId::random());
+        assert!(deliveries
+            .failed(&policy, first, delivery(1), &Error::MissingResponse)
+            .is_none());
+        let (last_tried, retried) = deliveries.next_retry().await;
+        assert_eq!(last_tried, first);
+        assert_eq!(retried.tried, vec![first]);
+
+        let mut retried = retried;
+        retried.attempts += 1;
+        assert!(deliveries
+            .failed(&policy, second, retried, &Error::MissingResponse)
+            .is_none());
+        let (last_tried, retried) = deliveries.next_retry().await;
+        assert_eq!(last_tried, second);
+        assert_eq!(retried.tried, vec![first, second]);
+    }
+}
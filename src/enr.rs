@@ -0,0 +1,328 @@
+//! Decoding of the ENRs peers share over peer exchange
+use enr::{k256::ecdsa::SigningKey, EnrPublicKey};
+use libp2p::{identity, multiaddr::Protocol, Multiaddr, PeerId};
+use std::net::IpAddr;
+
+use crate::Error;
+
+type Enr = enr::Enr<SigningKey>;
+
+/// Size of the shard bit vector in an `rsv` field, one bit per shard
+const SHARD_BIT_VECTOR_SIZE: usize = 128;
+
+/// Capabilities a node advertises in the `waku2` field of its ENR
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    pub const RELAY: Self = Self(1 << 0);
+    pub const STORE: Self = Self(1 << 1);
+    pub const FILTER: Self = Self(1 << 2);
+    pub const LIGHT_PUSH: Self = Self(1 << 3);
+
+    /// Whether all capabilities of `other` are advertised
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Shards a node advertises in the `rs` or `rsv` field of its ENR
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayShards {
+    pub cluster_id: u16,
+    pub shards: Vec<u16>,
+}
+
+impl RelayShards {
+    /// Decode the `rs` field: cluster id, shard count and a list of shard ids
+    fn from_list(bytes: &[u8]) -> Result<Self, Error> {
+        let (cluster_id, rest) = split_u16(bytes)?;
+        let (&count, shards) = rest
+            .split_first()
+            .ok_or(Error::Enr("Shard list is too short"))?;
+        if shards.len() != 2 * usize::from(count) {
+            return Err(Error::Enr("Shard list length doesn't match its count"));
+        }
+        let shards = shards
+            .chunks_exact(2)
+            .map(|shard| u16::from_be_bytes([shard[0], shard[1]]))
+            .collect();
+        Ok(Self { cluster_id, shards })
+    }
+
+    /// Decode the `rsv` field: cluster id and a bit vector of shards
+    fn from_bit_vector(bytes: &[u8]) -> Result<Self, Error> {
+        let (cluster_id, bits) = split_u16(bytes)?;
+        if bits.len() != SHARD_BIT_VECTOR_SIZE {
+            return Err(Error::Enr("Shard bit vector has the wrong size"));
+        }
+        let shards = (0..SHARD_BIT_VECTOR_SIZE as u16 * 8)
+            .filter(|shard| bits[usize::from(shard / 8)] & (1 << (shard % 8)) != 0)
+            .collect();
+        Ok(Self { cluster_id, shards })
+    }
+}
+
+/// A Waku peer as described by its ENR
+#[derive(Clone, Debug)]
+pub struct EnrPeer {
+    pub peer_id: PeerId,
+    pub public_key: identity::PublicKey,
+    /// Addresses to dial the peer on, ending with its peer id
+    pub addresses: Vec<Multiaddr>,
+    pub capabilities: Capabilities,
+    pub shards: Option<RelayShards>,
+    /// Sequence number of the record, higher is newer
+    pub seq: u64,
+    /// The RLP encoded record
+    pub enr: Vec<u8>,
+}
+
+impl EnrPeer {
+    /// Decode an RLP encoded ENR, verifying its v4 signature
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let record: Enr =
+            rlp::decode(bytes).map_err(|_| Error::Enr("Not a valid, signed v4 record"))?;
+
+        let public_key =
+            identity::secp256k1::PublicKey::try_from_bytes(&record.public_key().encode())
+                .map_err(|_| Error::Enr("Invalid secp256k1 public key"))?;
+        let public_key = identity::PublicKey::from(public_key);
+        let peer_id = public_key.to_peer_id();
+
+        let mut addresses = Vec::new();
+        if let (Some(ip), Some(tcp)) = (record.ip4(), record.tcp4()) {
+            addresses.push(tcp_address(ip.into(), tcp));
+        }
+        if let (Some(ip), Some(tcp)) = (record.ip6(), record.tcp6()) {
+            addresses.push(tcp_address(ip.into(), tcp));
+        }
+        if let Some(multiaddrs) = record.get("multiaddrs") {
+            addresses.extend(decode_multiaddrs(multiaddrs)?);
+        }
+        let addresses = addresses
+            .into_iter()
+            .map(|address| match address.iter().last() {
+                Some(Protocol::P2p(_)) => address,
+                _ => address.with(Protocol::P2p(peer_id)),
+            })
+            .collect();
+
+        let capabilities = match record.get("waku2") {
+            Some([bits]) => Capabilities(*bits),
+            Some(_) => return Err(Error::Enr("Capabilities are not a single byte")),
+            None => Capabilities::default(),
+        };
+        let shards = match (record.get("rs"), record.get("rsv")) {
+            (Some(list), _) => Some(RelayShards::from_list(list)?),
+            (None, Some(bit_vector)) => Some(RelayShards::from_bit_vector(bit_vector)?),
+            (None, None) => None,
+        };
+
+        Ok(Self {
+            peer_id,
+            public_key,
+            addresses,
+            capabilities,
+            shards,
+            seq: record.seq(),
+            enr: bytes.to_vec(),
+        })
+    }
+}
+
+fn tcp_address(ip: IpAddr, port: u16) -> Multiaddr {
+    Multiaddr::from(ip).with(Protocol::Tcp(port))
+}
+
+/// Decode the `multiaddrs` field: multiaddrs each prefixed by a big endian u16 length
+fn decode_multiaddrs(mut bytes: &[u8]) -> Result<Vec<Multiaddr>, Error> {
+    let mut addresses = Vec::new();
+    while !bytes.is_empty() {
+        let (length, rest) = split_u16(bytes)?;
+        let length = usize::from(length);
+        if rest.len() < length {
+            return Err(Error::Enr("Multiaddr is longer than the field"));
+        }
+        let address = Multiaddr::try_from(rest[..length].to_vec())
+            .map_err(|_| Error::Enr("Invalid multiaddr"))?;
+        addresses.push(address);
+        bytes = &rest[length..];
+    }
+    Ok(addresses)
+}
+
+fn split_u16(bytes: &[u8]) -> Result<(u16, &[u8]), Error> {
+    match bytes {
+        [high, low, rest @ ..] => Ok((u16::from_be_bytes([*high, *low]), rest)),
+        _ => Err(Error::Enr("Field is too short")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, str::FromStr};
+
+    use super::*;
+
+    /// The example record of EIP-778, signed by the key below
+    const EIP_778_ENR: &str = "enr:-IS4QHCYrYZbAKWCBRlAy5zzaDZXJBGkcnh4MHcBFZntXNFrdvJjX04jRzjzCBOonrkTfj499SZuOh8R33Ls8RRcy5wBgmlkgnY0gmlwhH8AAAGJc2VjcDI1NmsxoQPKY0yuDUmstAHYpMa2_oxVtw0RW_QAdpzBQA8yWM0xOIN1ZHCCdl8";
+    const EIP_778_PUBLIC_KEY: &str =
+        "03ca634cae0d49acb401d8a4c6b6fe8c55b70d115bf400769cc1400f3258cd3138";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    fn key() -> SigningKey {
+        SigningKey::from_slice(&[1; 32]).unwrap()
+    }
+
+    /// A record as nwaku builds them, with the given extra fields
+    fn record(fields: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = Enr::builder();
+        builder.ip4(Ipv4Addr::new(10, 0, 0, 1)).tcp4(60000);
+        for (key, value) in fields {
+            builder.add_value(key, value);
+        }
+        rlp::encode(&builder.build(&key()).unwrap()).to_vec()
+    }
+
+    fn multiaddrs(addresses: &[Multiaddr]) -> Vec<u8> {
+        addresses
+            .iter()
+            .flat_map(|address| {
+                let bytes = address.to_vec();
+                (bytes.len() as u16).to_be_bytes().into_iter().chain(bytes)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decodes_eip_778_record() {
+        let record = Enr::from_str(EIP_778_ENR).unwrap();
+        let peer = EnrPeer::decode(&rlp::encode(&record)).unwrap();
+        let public_key = peer.public_key.clone().try_into_secp256k1().unwrap();
+        assert_eq!(hex(&public_key.to_bytes()), EIP_778_PUBLIC_KEY);
+        assert_eq!(peer.peer_id, peer.public_key.to_peer_id());
+        assert_eq!(peer.seq, 1);
+        // Only a UDP port, nothing to dial over TCP
+        assert!(peer.addresses.is_empty());
+        assert_eq!(peer.capabilities, Capabilities::default());
+        assert_eq!(peer.shards, None);
+    }
+
+    #[test]
+    fn rejects_tampered_signature() {
+        let mut bytes = rlp::encode(&Enr::from_str(EIP_778_ENR).unwrap()).to_vec();
+        // First byte of the signature, after the list and string headers
+        bytes[4] ^= 1;
+        assert!(EnrPeer::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn decodes_waku_fields() {
+        let peer = EnrPeer::decode(&record(&[
+            ("waku2", &[0b1101]),
+            ("rs", &[0, 1, 2, 0, 0, 0, 5]),
+        ]))
+        .unwrap();
+        assert!(peer.capabilities.contains(Capabilities::RELAY));
+        assert!(!peer.capabilities.contains(Capabilities::STORE));
+        assert!(peer.capabilities.contains(Capabilities::FILTER));
+        assert!(peer.capabilities.contains(Capabilities::LIGHT_PUSH));
+        assert_eq!(
+            peer.shards,
+            Some(RelayShards {
+                cluster_id: 1,
+                shards: vec![0, 5],
+            })
+        );
+        let address: Multiaddr = "/ip4/10.0.0.1/tcp/60000".parse().unwrap();
+        assert_eq!(
+            peer.addresses,
+            vec![address.with(Protocol::P2p(peer.peer_id))]
+        );
+    }
+
+    #[test]
+    fn decodes_shard_bit_vector_least_significant_bit_first() {
+        let mut rsv = vec![0, 1];
+        rsv.extend([0; SHARD_BIT_VECTOR_SIZE]);
+        rsv[2] = 0b0000_1001;
+        rsv[3] = 0b0000_0010;
+        rsv[2 + SHARD_BIT_VECTOR_SIZE - 1] = 0b1000_0000;
+        let peer = EnrPeer::decode(&record(&[("rsv", &rsv)])).unwrap();
+        assert_eq!(
+            peer.shards,
+            Some(RelayShards {
+                cluster_id: 1,
+                shards: vec![0, 3, 9, 1023],
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_multiaddrs() {
+        let peer_id = PeerId::from(identity::PublicKey::from(
+            identity::secp256k1::PublicKey::try_from_bytes(&key().verifying_key().to_sec1_bytes())
+                .unwrap(),
+        ));
+        let websocket: Multiaddr = "/dns4/node.example.org/tcp/8000/wss".parse().unwrap();
+        let circuit: Multiaddr = format!("/ip4/1.2.3.4/tcp/30303/p2p/{peer_id}")
+            .parse()
+            .unwrap();
+        let peer = EnrPeer::decode(&record(&[(
+            "multiaddrs",
+            &multiaddrs(&[websocket.clone(), circuit.clone()]),
+        )]))
+        .unwrap();
+        assert_eq!(peer.peer_id, peer_id);
+        assert_eq!(
+            peer.addresses,
+            vec![
+                "/ip4/10.0.0.1/tcp/60000"
+                    .parse::<Multiaddr>()
+                    .unwrap()
+                    .with(Protocol::P2p(peer_id)),
+                websocket.with(Protocol::P2p(peer_id)),
+                circuit,
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_shard_list() {
+        // Count of 2 with a single shard
+        assert!(EnrPeer::decode(&record(&[("rs", &[0, 1, 2, 0, 0])])).is_err());
+        // No room for the count
+        assert!(EnrPeer::decode(&record(&[("rs", &[0, 1])])).is_err());
+        assert!(EnrPeer::decode(&record(&[("rs", &[0])])).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_shard_bit_vector() {
+        let mut rsv = vec![0, 1];
+        rsv.extend([0; SHARD_BIT_VECTOR_SIZE - 1]);
+        assert!(EnrPeer::decode(&record(&[("rsv", &rsv)])).is_err());
+        rsv.extend([0; 2]);
+        assert!(EnrPeer::decode(&record(&[("rsv", &rsv)])).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_multiaddrs() {
+        let address = "/ip4/1.2.3.4/tcp/30303".parse::<Multiaddr>().unwrap();
+        let mut truncated = multiaddrs(&[address]);
+        truncated.pop();
+        assert!(EnrPeer::decode(&record(&[("multiaddrs", &truncated)])).is_err());
+        // A length prefix cut short
+        assert!(EnrPeer::decode(&record(&[("multiaddrs", &[0])])).is_err());
+        // Not a multiaddr protocol code
+        assert!(EnrPeer::decode(&record(&[("multiaddrs", &[0, 2, 0xff, 0xff])])).is_err());
+    }
+
+    #[test]
+    fn rejects_multi_byte_capabilities() {
+        assert!(EnrPeer::decode(&record(&[("waku2", &[1, 0])])).is_err());
+    }
+}
@@ -1,11 +1,13 @@
 //! Codec for the filter-subscribe protocol
-use async_trait::async_trait;
-use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use libp2p::{request_response, StreamProtocol};
-use prost::Message;
-use std::io;
+use crate::{
+    codec::{Framing, ProtoCodec},
+    pending::EchoedRequestId,
+};
 
-const MAX_FILTER_RPC_SIZE: u64 = 1024 * 1024 * 1024;
+/// Max request size in bytes, enough for a subscription to 100 content topics
+const REQUEST_SIZE_MAXIMUM: usize = 64 * 1024;
+/// Max response size in bytes
+const RESPONSE_SIZE_MAXIMUM: usize = 64 * 1024;
 
 pub mod messages {
     include!(concat!(env!("OUT_DIR"), "/waku.filter.v2.rs"));
@@ -13,69 +15,219 @@ pub mod messages {
 
 pub const PROTOCOL_NAME: &str = "/vac/waku/filter-subscribe/2.0.0-beta1";
 
+/// Status code of a successful filter request
+pub const STATUS_OK: u32 = 200;
+const STATUS_BAD_REQUEST: u32 = 400;
+const STATUS_NOT_FOUND: u32 = 404;
+const STATUS_TOO_MANY_REQUESTS: u32 = 429;
+const STATUS_SERVICE_UNAVAILABLE: u32 = 503;
+
+/// How nwaku describes refusals due to its subscription limits
+const SUBSCRIPTION_LIMIT_DESC: &str = "maximum number of";
+
+/// A filter request refused by the service node
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum FilterError {
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    /// No subscription to ping or unsubscribe from
+    #[error("Subscription not found: {0}")]
+    NotFound(String),
+    /// The service node reached its limit of subscribers or content topics per subscriber
+    #[error("Too many subscriptions: {0}")]
+    TooManySubscriptions(String),
+    /// Requests got rate limited
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+    #[error("Status {status_code}: {status_desc}")]
+    Other {
+        status_code: u32,
+        status_desc: String,
+    },
+}
+
+impl FilterError {
+    /// The refusal as described to the client
+    fn description(&self) -> &str {
+        match self {
+            Self::BadRequest(description)
+            | Self::NotFound(description)
+            | Self::TooManySubscriptions(description)
+            | Self::TooManyRequests(description)
+            | Self::ServiceUnavailable(description) => description,
+            Self::Other { status_desc, .. } => status_desc,
+        }
+    }
+}
+
 pub use messages::*;
 
-#[derive(Clone, Default)]
-pub struct Codec {}
-
-#[async_trait]
-impl request_response::Codec for Codec {
-    type Protocol = StreamProtocol;
-    type Request = messages::FilterSubscribeRequest;
-    type Response = messages::FilterSubscribeResponse;
-
-    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        let mut vec = Vec::with_capacity(MAX_FILTER_RPC_SIZE as usize);
-        io.read_to_end(&mut vec).await?;
-        let request = Self::Request::decode_length_delimited(&vec[..])?;
-        Ok(request)
+pub type Codec = ProtoCodec<messages::FilterSubscribeRequest, messages::FilterSubscribeResponse>;
+
+pub fn codec() -> Codec {
+    ProtoCodec::new(
+        Framing::LengthPrefixed(REQUEST_SIZE_MAXIMUM),
+        Framing::LengthPrefixed(RESPONSE_SIZE_MAXIMUM),
+    )
+}
+
+impl FilterSubscribeResponse {
+    /// Response to a filter request, with the outcome of serving it
+    pub(crate) fn new(request_id: String, result: Result<(), FilterError>) -> Self {
+        let (status_code, status_desc) = match result {
+            Ok(()) => (STATUS_OK, None),
+            Err(error) => {
+                let status_code = match error {
+                    FilterError::BadRequest(_) => STATUS_BAD_REQUEST,
+                    FilterError::NotFound(_) => STATUS_NOT_FOUND,
+                    FilterError::TooManySubscriptions(_) | FilterError::ServiceUnavailable(_) => {
+                        STATUS_SERVICE_UNAVAILABLE
+                    }
+                    FilterError::TooManyRequests(_) => STATUS_TOO_MANY_REQUESTS,
+                    FilterError::Other { status_code, .. } => status_code,
+                };
+                (status_code, Some(error.description().to_string()))
+            }
+        };
+        Self {
+            request_id,
+            status_code,
+            status_desc,
+        }
+    }
+
+    /// The response if its status code is a success, the typed refusal otherwise
+    pub fn into_result(self) -> Result<Self, FilterError> {
+        let status_desc = self.status_desc.clone().unwrap_or_default();
+        Err(match self.status_code {
+            STATUS_OK => return Ok(self),
+            STATUS_BAD_REQUEST | STATUS_SERVICE_UNAVAILABLE
+                if status_desc.contains(SUBSCRIPTION_LIMIT_DESC) =>
+            {
+                FilterError::TooManySubscriptions(status_desc)
+            }
+            STATUS_BAD_REQUEST => FilterError::BadRequest(status_desc),
+            STATUS_NOT_FOUND => FilterError::NotFound(status_desc),
+            STATUS_TOO_MANY_REQUESTS => FilterError::TooManyRequests(status_desc),
+            STATUS_SERVICE_UNAVAILABLE => FilterError::ServiceUnavailable(status_desc),
+            status_code => FilterError::Other {
+                status_code,
+                status_desc,
+            },
+        })
+    }
+}
+
+impl EchoedRequestId for messages::FilterSubscribeResponse {
+    fn request_id(&self) -> Option<&str> {
+        Some(&self.request_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status_code: u32, status_desc: &str) -> FilterSubscribeResponse {
+        FilterSubscribeResponse {
+            request_id: "request".to_string(),
+            status_code,
+            status_desc: Some(status_desc.to_string()),
+        }
+    }
+
+    fn refusal(status_code: u32, status_desc: &str) -> FilterError {
+        response(status_code, status_desc)
+            .into_result()
+            .unwrap_err()
+    }
+
+    #[test]
+    fn accepts_success() {
+        let response = response(200, "OK");
+        assert_eq!(response.clone().into_result(), Ok(response));
     }
 
-    async fn read_response<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-    ) -> io::Result<Self::Response>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        let mut vec = Vec::with_capacity(MAX_FILTER_RPC_SIZE as usize);
-
-        io.read_to_end(&mut vec).await?;
-        let response = Self::Response::decode_length_delimited(&vec[..])?;
-        Ok(response)
+    #[test]
+    fn maps_status_codes_to_refusals() {
+        let desc = "description".to_string();
+        assert_eq!(refusal(400, &desc), FilterError::BadRequest(desc.clone()));
+        assert_eq!(refusal(404, &desc), FilterError::NotFound(desc.clone()));
+        assert_eq!(
+            refusal(429, &desc),
+            FilterError::TooManyRequests(desc.clone())
+        );
+        assert_eq!(
+            refusal(503, &desc),
+            FilterError::ServiceUnavailable(desc.clone())
+        );
+        assert_eq!(
+            refusal(500, &desc),
+            FilterError::Other {
+                status_code: 500,
+                status_desc: desc,
+            }
+        );
+        let missing_desc = FilterSubscribeResponse {
+            status_desc: None,
+            ..response(404, "")
+        };
+        assert_eq!(
+            missing_desc.into_result(),
+            Err(FilterError::NotFound(String::new()))
+        );
     }
 
-    async fn write_request<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-        req: Self::Request,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        let buf = req.encode_length_delimited_to_vec();
-        io.write_all(buf.as_ref()).await?;
-
-        Ok(())
+    #[test]
+    fn tells_subscription_limits_by_nwaku_wording() {
+        for (status_code, status_desc) in [
+            (503, "node has reached maximum number of subscriptions"),
+            (400, "peer has reached maximum number of filter criteria"),
+        ] {
+            assert_eq!(
+                refusal(status_code, status_desc),
+                FilterError::TooManySubscriptions(status_desc.to_string())
+            );
+        }
+        // Other refusals with these status codes
+        assert!(matches!(
+            refusal(400, "pubsub topic and content topics must be specified"),
+            FilterError::BadRequest(_)
+        ));
+        assert!(matches!(
+            refusal(503, "shutting down"),
+            FilterError::ServiceUnavailable(_)
+        ));
+        assert!(matches!(
+            refusal(404, "maximum number of subscriptions"),
+            FilterError::NotFound(_)
+        ));
     }
 
-    async fn write_response<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-        resp: Self::Response,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        let buf = resp.encode_length_delimited_to_vec();
-        io.write_all(buf.as_ref()).await?;
-
-        Ok(())
+    #[test]
+    fn answers_refusals_with_their_status_codes() {
+        let desc = "description".to_string();
+        for (error, status_code) in [
+            (FilterError::BadRequest(desc.clone()), 400),
+            (FilterError::NotFound(desc.clone()), 404),
+            (FilterError::TooManySubscriptions(desc.clone()), 503),
+            (FilterError::TooManyRequests(desc.clone()), 429),
+            (FilterError::ServiceUnavailable(desc.clone()), 503),
+            (
+                FilterError::Other {
+                    status_code: 500,
+                    status_desc: desc.clone(),
+                },
+                500,
+            ),
+        ] {
+            let response = FilterSubscribeResponse::new("request".to_string(), Err(error));
+            assert_eq!(response.status_code, status_code);
+            assert_eq!(response.status_desc.as_deref(), Some(desc.as_str()));
+        }
+        let response = FilterSubscribeResponse::new("request".to_string(), Ok(()));
+        assert_eq!((response.status_code, response.status_desc), (200, None));
     }
 }
@@ -0,0 +1,20 @@
+//! Codec for the filter-push protocol
+use crate::{
+    codec::{Framing, ProtoCodec},
+    message::MAX_ENVELOPED_MESSAGE_SIZE,
+};
+
+pub const PROTOCOL_NAME: &str = "/vac/waku/filter-push/2.0.0-beta1";
+
+pub use crate::filter::messages::MessagePush;
+
+/// The filter-push protocol is one-way: the service node writes a single
+/// [`MessagePush`] and closes the stream, so there is no response to read.
+pub type Codec = ProtoCodec<MessagePush, ()>;
+
+pub fn codec() -> Codec {
+    ProtoCodec::new(
+        Framing::LengthPrefixed(MAX_ENVELOPED_MESSAGE_SIZE),
+        Framing::UntilEof(0),
+    )
+}
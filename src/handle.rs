@@ -0,0 +1,240 @@
+//! A cloneable handle to a node running in a background task
+use std::collections::BTreeMap;
+
+use libp2p::PeerId;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    ContentTopic, Error, FilterSubscribeResponse, MessageHash, PeerExchangeResponse, PubsubTopic,
+    PushOutcome, RejectReason, StoreQuery, StoreQueryResponse, Subscription,
+};
+
+/// Reply channel for the outcome of a command
+pub(crate) type Reply<T> = oneshot::Sender<Result<T, Error>>;
+
+/// A request from a [`WakuLightNodeHandle`] to the node driver
+pub(crate) enum Command {
+    Push {
+        peer: PeerId,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topic: ContentTopic,
+        payload: Vec<u8>,
+        timestamp: Option<i64>,
+        reply: Reply<PushOutcome>,
+    },
+    Subscribe {
+        peer: PeerId,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topics: Vec<ContentTopic>,
+        reply: Reply<Vec<FilterSubscribeResponse>>,
+    },
+    Unsubscribe {
+        peer: PeerId,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topics: Vec<ContentTopic>,
+        reply: Reply<Vec<FilterSubscribeResponse>>,
+    },
+    UnsubscribeAll {
+        peer: PeerId,
+        reply: Reply<FilterSubscribeResponse>,
+    },
+    Subscriptions {
+        reply: Reply<Vec<Subscription>>,
+    },
+    Rejections {
+        reply: Reply<BTreeMap<RejectReason, u64>>,
+    },
+    RelayPublish {
+        pubsub_topic: Option<PubsubTopic>,
+        content_topic: ContentTopic,
+        payload: Vec<u8>,
+        timestamp: Option<i64>,
+        reply: Reply<MessageHash>,
+    },
+    RelaySubscribe {
+        pubsub_topic: PubsubTopic,
+        reply: Reply<bool>,
+    },
+    RelayUnsubscribe {
+        pubsub_topic: PubsubTopic,
+        reply: Reply<bool>,
+    },
+    Query {
+        peer: PeerId,
+        query: StoreQuery,
+        reply: Reply<StoreQueryResponse>,
+    },
+    Peers {
+        peer: PeerId,
+        reply: Reply<PeerExchangeResponse>,
+    },
+    Shutdown {
+        done: oneshot::Sender<()>,
+    },
+}
+
+/// Handle to a node spawned with [`crate::WakuLightNode::spawn`]
+///
+/// Cheap to clone, every clone talks to the same node.
+#[derive(Clone, Debug)]
+pub struct WakuLightNodeHandle {
+    commands: mpsc::Sender<Command>,
+}
+
+impl WakuLightNodeHandle {
+    pub(crate) fn new(commands: mpsc::Sender<Command>) -> Self {
+        Self { commands }
+    }
+
+    /// Send a Waku message via light-push, waiting for the final outcome after any retries
+    ///
+    /// The pubsub topic is autosharded from the content topic unless given.
+    /// The timestamp is in Unix nanoseconds, the current time unless given.
+    pub async fn send(
+        &self,
+        peer: PeerId,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topic: ContentTopic,
+        payload: Vec<u8>,
+        timestamp: Option<i64>,
+    ) -> Result<PushOutcome, Error> {
+        self.request(|reply| Command::Push {
+            peer,
+            pubsub_topic,
+            content_topic,
+            payload,
+            timestamp,
+            reply,
+        })
+        .await
+    }
+
+    /// Subscribe to topic(s) using the filter protocol
+    ///
+    /// Without a pubsub topic, content topics get autosharded and one request is sent per shard.
+    pub async fn subscribe(
+        &self,
+        peer: PeerId,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topics: Vec<ContentTopic>,
+    ) -> Result<Vec<FilterSubscribeResponse>, Error> {
+        self.request(|reply| Command::Subscribe {
+            peer,
+            pubsub_topic,
+            content_topics,
+            reply,
+        })
+        .await
+    }
+
+    /// Unsubscribe from topic(s) using the filter protocol
+    ///
+    /// Without a pubsub topic, content topics get autosharded and one request is sent per shard.
+    pub async fn unsubscribe(
+        &self,
+        peer: PeerId,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topics: Vec<ContentTopic>,
+    ) -> Result<Vec<FilterSubscribeResponse>, Error> {
+        self.request(|reply| Command::Unsubscribe {
+            peer,
+            pubsub_topic,
+            content_topics,
+            reply,
+        })
+        .await
+    }
+
+    /// Unsubscribe from all topics a filter service node holds subscriptions for
+    pub async fn unsubscribe_all(&self, peer: PeerId) -> Result<FilterSubscribeResponse, Error> {
+        self.request(|reply| Command::UnsubscribeAll { peer, reply })
+            .await
+    }
+
+    /// Active filter subscriptions, those service nodes accepted
+    pub async fn subscriptions(&self) -> Result<Vec<Subscription>, Error> {
+        self.request(|reply| Command::Subscriptions { reply }).await
+    }
+
+    /// Publish a Waku message via relay
+    ///
+    /// The pubsub topic is autosharded from the content topic unless given.
+    /// The timestamp is in Unix nanoseconds, the current time unless given.
+    pub async fn relay_publish(
+        &self,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topic: ContentTopic,
+        payload: Vec<u8>,
+        timestamp: Option<i64>,
+    ) -> Result<MessageHash, Error> {
+        self.request(|reply| Command::RelayPublish {
+            pubsub_topic,
+            content_topic,
+            payload,
+            timestamp,
+            reply,
+        })
+        .await
+    }
+
+    /// Subscribe to a pubsub topic via relay
+    ///
+    /// Returns whether the node wasn't subscribed already.
+    pub async fn relay_subscribe(&self, pubsub_topic: PubsubTopic) -> Result<bool, Error> {
+        self.request(|reply| Command::RelaySubscribe {
+            pubsub_topic,
+            reply,
+        })
+        .await
+    }
+
+    /// Unsubscribe from a pubsub topic via relay
+    ///
+    /// Returns whether the node was subscribed.
+    pub async fn relay_unsubscribe(&self, pubsub_topic: PubsubTopic) -> Result<bool, Error> {
+        self.request(|reply| Command::RelayUnsubscribe {
+            pubsub_topic,
+            reply,
+        })
+        .await
+    }
+
+    /// Number of relayed messages rejected by validation, by reason
+    pub async fn rejections(&self) -> Result<BTreeMap<RejectReason, u64>, Error> {
+        self.request(|reply| Command::Rejections { reply }).await
+    }
+
+    /// Query a store node for historical messages
+    pub async fn query(
+        &self,
+        peer: PeerId,
+        query: StoreQuery,
+    ) -> Result<StoreQueryResponse, Error> {
+        self.request(|reply| Command::Query { peer, query, reply })
+            .await
+    }
+
+    /// Request peers via peer exchange
+    pub async fn peers(&self, peer: PeerId) -> Result<PeerExchangeResponse, Error> {
+        self.request(|reply| Command::Peers { peer, reply }).await
+    }
+
+    /// Stop the node, waiting until it has shut down
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        let (done, stopped) = oneshot::channel();
+        self.commands
+            .send(Command::Shutdown { done })
+            .await
+            .map_err(|_| Error::NodeStopped)?;
+        stopped.await.map_err(|_| Error::NodeStopped)
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(Reply<T>) -> Command) -> Result<T, Error> {
+        let (reply, outcome) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .await
+            .map_err(|_| Error::NodeStopped)?;
+        outcome.await.map_err(|_| Error::NodeStopped)?
+    }
+}
@@ -1,27 +1,141 @@
-use filter::messages::filter_subscribe_request::FilterSubscribeType;
+use delivery::{Deliveries, Delivery};
+use handle::Command;
 use libp2p::{
-    identity::Keypair, noise, request_response, swarm::NetworkBehaviour, tcp, yamux, Multiaddr,
-    PeerId, StreamProtocol, Swarm,
+    futures::StreamExt,
+    gossipsub,
+    identity::Keypair,
+    noise, request_response,
+    request_response::OutboundRequestId,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm,
 };
-use log::{error, info};
+use log::{debug, info};
+use message::SeenMessages;
+use peer_store::PeerStore;
+use pending::PendingRequests;
+use prost::Message as _;
+use rate_limit::RateLimiter;
+use subscribers::Subscribers;
+use subscriptions::{FilterRequest, Outcome, Subscriptions};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{self, Interval, MissedTickBehavior},
+};
+use validation::Validation;
 
+mod codec;
+mod delivery;
+mod enr;
 mod filter;
+mod filter_push;
+mod handle;
 mod light_push;
+mod light_push_v3;
+mod message;
 mod metadata;
 mod peer_exchange;
+mod peer_store;
+mod pending;
+mod rate_limit;
+mod relay;
+mod sharding;
+mod store;
+mod subscribers;
+mod subscriptions;
+mod topic;
+mod validation;
+
+pub use delivery::RetryPolicy;
+pub use enr::{Capabilities, EnrPeer, RelayShards};
+pub use filter::{FilterError, FilterSubscribeResponse};
+pub use handle::WakuLightNodeHandle;
+pub use light_push::{PushError, PushOutcome, PushSink, PushVersion};
+pub use message::{message_hash, MessageHash, WakuMessage};
+pub use peer_exchange::messages::PeerExchangeResponse;
+pub use rate_limit::RateLimit;
+pub use store::{Direction, StoreError, StoreQuery, StoreQueryResponse};
+pub use subscribers::SubscriberLimits;
+pub use subscriptions::Subscription;
+pub use topic::{ContentTopic, PubsubTopic};
+pub use validation::{RejectReason, Validator, Verdict};
 
 use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     num::TryFromIntError,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    str::FromStr,
+    time::Duration,
 };
 
-const DEFAULT_PUBSUB_TOPIC: &str = "/waku/2/default-waku/proto";
+/// Commands queued from handles before they have to wait for the driver
+const COMMAND_CHANNEL_CAPACITY: usize = 64;
+/// Events queued for the application before the node stops being driven
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+/// Same tolerance nwaku applies to message timestamps
+const DEFAULT_MAX_TIMESTAMP_DRIFT: Duration = Duration::from_secs(20);
+/// The Waku Network
+const DEFAULT_CLUSTER_ID: u16 = 1;
+/// Shards of The Waku Network
+const DEFAULT_SHARD_COUNT: u16 = 8;
+/// Well within the time nwaku keeps idle filter subscriptions for
+const DEFAULT_FILTER_PING_INTERVAL: Duration = Duration::from_secs(60);
+/// A single service node per subscription, as subscribed with
+const DEFAULT_FILTER_REDUNDANCY: usize = 1;
+/// Plenty for a light client, while keeping floods of pushes out
+const DEFAULT_LIGHT_PUSH_RATE_LIMIT: RateLimit = RateLimit {
+    requests: 10,
+    period: Duration::from_secs(1),
+};
+/// Plenty for a light client looking for peers now and then
+const DEFAULT_PEER_EXCHANGE_RATE_LIMIT: RateLimit = RateLimit {
+    requests: 10,
+    period: Duration::from_secs(60),
+};
+/// Plenty for a light client pinging and managing its subscriptions
+const DEFAULT_FILTER_RATE_LIMIT: RateLimit = RateLimit {
+    requests: 30,
+    period: Duration::from_secs(60),
+};
 
 pub struct WakuLightNodeConfig {
     /// Initial nodes to connect to
     pub peers: Vec<Multiaddr>,
     /// A libp2p identity keypair
     pub keypair: Keypair,
+    /// Max difference between the timestamp of a received message and local time
+    pub max_timestamp_drift: Duration,
+    /// Network the node belongs to, peers of other clusters get disconnected
+    pub cluster_id: u16,
+    /// Shards of the cluster the node is interested in
+    pub shards: Vec<u16>,
+    /// Number of shards in the cluster, content topics get autosharded among them
+    pub shard_count: u16,
+    /// How often filter service nodes get pinged to keep subscriptions alive, and lost
+    /// subscriptions get renewed, must not be zero
+    pub filter_ping_interval: Duration,
+    /// Number of distinct filter service nodes every subscription is held by
+    pub filter_redundancy: usize,
+    /// How failed light pushes get retried with other service nodes
+    pub light_push_retry: RetryPolicy,
+    /// Take part in relay, publishing and receiving messages via gossipsub
+    pub relay: bool,
+    /// Application checks of relayed messages, run after the built-in ones
+    pub relay_validators: Vec<Box<dyn Validator>>,
+    /// Serve light push, publishing accepted messages via relay unless a sink is given
+    pub light_push_service: bool,
+    /// Where to publish messages pushed to us instead of relay
+    pub light_push_sink: Option<Box<dyn PushSink>>,
+    /// Light push requests a single client may make
+    pub light_push_rate_limit: RateLimit,
+    /// Serve filter, pushing messages received via relay or light push to subscribers
+    pub filter_service: bool,
+    /// How many subscriptions the filter service holds, and for how long
+    pub filter_limits: SubscriberLimits,
+    /// Filter requests a single client may make
+    pub filter_rate_limit: RateLimit,
+    /// Serve peer exchange, sharing peers discovered so far
+    pub peer_exchange_service: bool,
+    /// Peer exchange requests a single client may make
+    pub peer_exchange_rate_limit: RateLimit,
 }
 
 impl WakuLightNodeConfig {
@@ -30,18 +144,92 @@ impl WakuLightNodeConfig {
         Self {
             keypair: keypair.unwrap_or(Keypair::generate_ed25519()),
             peers,
+            max_timestamp_drift: DEFAULT_MAX_TIMESTAMP_DRIFT,
+            cluster_id: DEFAULT_CLUSTER_ID,
+            shards: (0..DEFAULT_SHARD_COUNT).collect(),
+            shard_count: DEFAULT_SHARD_COUNT,
+            filter_ping_interval: DEFAULT_FILTER_PING_INTERVAL,
+            filter_redundancy: DEFAULT_FILTER_REDUNDANCY,
+            light_push_retry: RetryPolicy::default(),
+            relay: false,
+            relay_validators: vec![],
+            light_push_service: false,
+            light_push_sink: None,
+            light_push_rate_limit: DEFAULT_LIGHT_PUSH_RATE_LIMIT,
+            filter_service: false,
+            filter_limits: SubscriberLimits::default(),
+            filter_rate_limit: DEFAULT_FILTER_RATE_LIMIT,
+            peer_exchange_service: false,
+            peer_exchange_rate_limit: DEFAULT_PEER_EXCHANGE_RATE_LIMIT,
         }
     }
+
+    /// Pubsub topics of the configured shards
+    pub fn pubsub_topics(&self) -> Vec<PubsubTopic> {
+        self.shards
+            .iter()
+            .map(|shard| PubsubTopic::new(self.cluster_id, *shard))
+            .collect()
+    }
 }
 
 pub struct WakuLightNode {
-    pub swarm: Swarm<WakuLightNodeBehaviour>,
+    swarm: Swarm<WakuLightNodeBehaviour>,
+    /// Requests made through the async API, awaiting their outcome
+    pending: Pending,
+    /// Events that came in while driving the swarm for an async request
+    events: VecDeque<SwarmEvent<WakuLightNodeEvent>>,
+    max_timestamp_drift: Duration,
+    peer_store: PeerStore,
+    cluster_id: u16,
+    shards: Vec<u16>,
+    shard_count: u16,
+    subscriptions: Subscriptions,
+    filter_ping_interval: Duration,
+    /// Ticks of filter subscription maintenance, started once the node is first driven
+    filter_ping: Option<Interval>,
+    filter_redundancy: usize,
+    /// Messages already received, from any of the redundant service nodes
+    seen_messages: SeenMessages,
+    deliveries: Deliveries,
+    light_push_retry: RetryPolicy,
+    /// Light push service nodes known not to speak v3
+    light_push_v2_peers: HashSet<PeerId>,
+    validation: Validation,
+    light_push_sink: Option<Box<dyn PushSink>>,
+    light_push_limiter: RateLimiter,
+    /// Subscriptions of the light clients we serve filter for
+    subscribers: Subscribers,
+    filter_limiter: RateLimiter,
+    peer_exchange_limiter: RateLimiter,
+    /// Shards connected peers on our cluster advertised in the metadata handshake
+    peer_shards: HashMap<PeerId, Vec<u16>>,
+}
+
+/// Pending requests of every protocol we act as a client for
+#[derive(Default)]
+struct Pending {
+    peer_exchange: PendingRequests<PeerExchangeResponse>,
+    light_push: PendingRequests<PushOutcome>,
+    light_push_v3: PendingRequests<PushOutcome>,
+    filter: PendingRequests<FilterSubscribeResponse>,
+    store: PendingRequests<StoreQueryResponse>,
 }
 
 impl WakuLightNode {
     pub fn new_with_config(config: WakuLightNodeConfig) -> Result<Self, Error> {
+        if config.filter_ping_interval.is_zero() {
+            return Err(Error::InvalidConfig(
+                "filter ping interval must not be zero",
+            ));
+        }
         let local_peer_id = PeerId::from(config.keypair.public());
         info!("Libp2p local peer id: {:?}", local_peer_id);
+        let validation = Validation::new(
+            config.max_timestamp_drift,
+            config.pubsub_topics(),
+            config.relay_validators,
+        );
 
         let mut swarm = libp2p::SwarmBuilder::with_existing_identity(config.keypair)
             .with_tokio()
@@ -51,7 +239,14 @@ impl WakuLightNode {
                 yamux::Config::default,
             )?
             .with_dns()?
-            .with_behaviour(|_key| WakuLightNodeBehaviour::new())
+            .with_behaviour(|_key| {
+                WakuLightNodeBehaviour::new(
+                    config.relay,
+                    config.light_push_service,
+                    config.filter_service,
+                    config.peer_exchange_service,
+                )
+            })
             .unwrap() // Infalliable
             .with_swarm_config(|config| {
                 config
@@ -66,119 +261,1653 @@ impl WakuLightNode {
         for peer in config.peers {
             swarm.dial(peer)?;
         }
-        Ok(Self { swarm })
+        Ok(Self {
+            swarm,
+            pending: Pending::default(),
+            events: VecDeque::new(),
+            max_timestamp_drift: config.max_timestamp_drift,
+            peer_store: PeerStore::default(),
+            cluster_id: config.cluster_id,
+            shards: config.shards,
+            shard_count: config.shard_count,
+            subscriptions: Subscriptions::default(),
+            filter_ping_interval: config.filter_ping_interval,
+            filter_ping: None,
+            filter_redundancy: config.filter_redundancy,
+            seen_messages: SeenMessages::default(),
+            deliveries: Deliveries::default(),
+            light_push_retry: config.light_push_retry,
+            light_push_v2_peers: HashSet::new(),
+            validation,
+            light_push_sink: config.light_push_sink,
+            light_push_limiter: RateLimiter::new(config.light_push_rate_limit),
+            subscribers: Subscribers::new(config.filter_limits),
+            filter_limiter: RateLimiter::new(config.filter_rate_limit),
+            peer_exchange_limiter: RateLimiter::new(config.peer_exchange_rate_limit),
+            peer_shards: HashMap::new(),
+        })
+    }
+
+    /// Run the node in a background task
+    ///
+    /// Returns a handle to control the node and the receiver of its events.
+    /// The node stops on [`WakuLightNodeHandle::shutdown`] or once all handles are dropped.
+    ///
+    /// No event gets dropped: while the receiver is full, the node stops being driven
+    /// until the application catches up, so commands awaiting a network outcome wait too.
+    /// Events get discarded once the receiver is dropped.
+    pub fn spawn(self) -> (WakuLightNodeHandle, mpsc::Receiver<WakuLightNodeEvent>) {
+        let (command_sender, commands) = mpsc::channel(COMMAND_CHANNEL_CAPACITY);
+        let (event_sender, events) = mpsc::channel(EVENT_CHANNEL_CAPACITY);
+        tokio::spawn(self.run(commands, event_sender));
+        (WakuLightNodeHandle::new(command_sender), events)
+    }
+
+    /// The node driver, owning the swarm for as long as the node runs
+    async fn run(
+        mut self,
+        mut commands: mpsc::Receiver<Command>,
+        events: mpsc::Sender<WakuLightNodeEvent>,
+    ) {
+        // An event waiting for room in the channel, holding back further ones
+        let mut blocked = None;
+        loop {
+            tokio::select! {
+                permit = events.reserve(), if blocked.is_some() => {
+                    let event = blocked.take().expect("Blocked event");
+                    // The application may have stopped listening, which is fine
+                    if let Ok(permit) = permit {
+                        permit.send(event);
+                    }
+                }
+                event = self.next_event(), if blocked.is_none() => {
+                    let event = match event {
+                        SwarmEvent::Behaviour(event) => event,
+                        SwarmEvent::ConnectionEstablished {
+                            peer_id,
+                            num_established,
+                            ..
+                        } if num_established.get() == 1 => WakuLightNodeEvent::PeerConnected(peer_id),
+                        SwarmEvent::ConnectionClosed {
+                            peer_id,
+                            num_established: 0,
+                            ..
+                        } => WakuLightNodeEvent::PeerDisconnected(peer_id),
+                        event => {
+                            debug!("Swarm event {:?}", event);
+                            continue;
+                        }
+                    };
+                    if let Err(mpsc::error::TrySendError::Full(event)) = events.try_send(event) {
+                        blocked = Some(event);
+                    }
+                }
+                command = commands.recv() => match command {
+                    Some(Command::Shutdown { done }) => {
+                        drop(self);
+                        let _ = done.send(());
+                        return;
+                    }
+                    Some(command) => self.handle_command(command),
+                    None => return,
+                }
+            }
+        }
+    }
+
+    /// Start the request a handle asked for, replying once its outcome is known
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Push {
+                peer,
+                pubsub_topic,
+                content_topic,
+                payload,
+                timestamp,
+                reply,
+            } => {
+                let message = self.new_message(pubsub_topic, content_topic, payload, timestamp);
+                match message {
+                    Ok((pubsub_topic, message)) => {
+                        self.deliver(&peer, pubsub_topic, message, Some(reply));
+                    }
+                    Err(error) => {
+                        let _ = reply.send(Err(error));
+                    }
+                }
+            }
+            Command::Subscribe {
+                peer,
+                pubsub_topic,
+                content_topics,
+                reply,
+            } => {
+                let request_ids = self.filter_subscribe(&peer, pubsub_topic, content_topics);
+                self.reply_with_filter_responses(request_ids, reply);
+            }
+            Command::Unsubscribe {
+                peer,
+                pubsub_topic,
+                content_topics,
+                reply,
+            } => {
+                let request_ids = self.filter_unsubscribe(&peer, pubsub_topic, content_topics);
+                self.reply_with_filter_responses(request_ids, reply);
+            }
+            Command::UnsubscribeAll { peer, reply } => {
+                let request_id = self.filter_unsubscribe_all(&peer);
+                self.pending.filter.wait(&request_id, reply);
+            }
+            Command::Subscriptions { reply } => {
+                let _ = reply.send(Ok(self.subscriptions()));
+            }
+            Command::Rejections { reply } => {
+                let _ = reply.send(Ok(self.rejections().clone()));
+            }
+            Command::RelayPublish {
+                pubsub_topic,
+                content_topic,
+                payload,
+                timestamp,
+                reply,
+            } => {
+                let _ =
+                    reply.send(self.relay_publish(pubsub_topic, content_topic, payload, timestamp));
+            }
+            Command::RelaySubscribe {
+                pubsub_topic,
+                reply,
+            } => {
+                let _ = reply.send(self.relay_subscribe(&pubsub_topic));
+            }
+            Command::RelayUnsubscribe {
+                pubsub_topic,
+                reply,
+            } => {
+                let _ = reply.send(self.relay_unsubscribe(&pubsub_topic));
+            }
+            Command::Query { peer, query, reply } => match self.store_query(&peer, query) {
+                Ok(request_id) => self.pending.store.wait(&request_id, reply),
+                Err(error) => {
+                    let _ = reply.send(Err(error));
+                }
+            },
+            Command::Peers { peer, reply } => {
+                let request_id = self.request_peers(&peer);
+                self.pending.peer_exchange.wait(&request_id, reply);
+            }
+            Command::Shutdown { .. } => unreachable!("Handled by the driver"),
+        }
+    }
+
+    /// Reply once all of the filter requests a command resulted in got a response
+    fn reply_with_filter_responses(
+        &mut self,
+        request_ids: Result<Vec<OutboundRequestId>, Error>,
+        reply: handle::Reply<Vec<FilterSubscribeResponse>>,
+    ) {
+        let request_ids = match request_ids {
+            Ok(request_ids) => request_ids,
+            Err(error) => {
+                let _ = reply.send(Err(error));
+                return;
+            }
+        };
+        let outcomes: Vec<_> = request_ids
+            .iter()
+            .map(|request_id| {
+                let (sender, outcome) = oneshot::channel();
+                self.pending.filter.wait(request_id, sender);
+                outcome
+            })
+            .collect();
+        tokio::spawn(async move {
+            let mut responses = Vec::with_capacity(outcomes.len());
+            for outcome in outcomes {
+                match outcome.await {
+                    Ok(Ok(response)) => responses.push(response),
+                    Ok(Err(error)) => {
+                        let _ = reply.send(Err(error));
+                        return;
+                    }
+                    Err(_) => {
+                        let _ = reply.send(Err(Error::NodeStopped));
+                        return;
+                    }
+                }
+            }
+            let _ = reply.send(Ok(responses));
+        });
+    }
+
+    /// Drive the node until the next event that isn't the outcome of an async request
+    pub async fn next_event(&mut self) -> SwarmEvent<WakuLightNodeEvent> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return event;
+            }
+            let event = self.next_swarm_event().await;
+            if let Some(event) = self.handle_event(event) {
+                return event;
+            }
+        }
+    }
+
+    /// Drive the node until the outcome of an async request arrives, keeping
+    /// other events for [`Self::next_event`]
+    async fn wait_for<T>(
+        &mut self,
+        mut outcome: oneshot::Receiver<Result<T, Error>>,
+    ) -> Result<T, Error> {
+        loop {
+            let event = self.next_swarm_event().await;
+            if let Some(event) = self.handle_event(event) {
+                self.events.push_back(event);
+            }
+            match outcome.try_recv() {
+                Ok(result) => return result,
+                Err(oneshot::error::TryRecvError::Empty) => continue,
+                Err(oneshot::error::TryRecvError::Closed) => return Err(Error::RequestDropped),
+            }
+        }
+    }
+
+    /// Drive the swarm until its next event, maintaining filter subscriptions meanwhile
+    async fn next_swarm_event(&mut self) -> SwarmEvent<WakuLightNodeEvent> {
+        loop {
+            tokio::select! {
+                event = self.swarm.select_next_some() => return event,
+                _ = self
+                    .filter_ping
+                    .get_or_insert_with(|| filter_ping(self.filter_ping_interval))
+                    .tick() => self.maintain_subscriptions(),
+                (peer, delivery) = self.deliveries.next_retry() => self.retry_delivery(peer, delivery),
+            }
+        }
+    }
+
+    /// Validate responses and received messages, and route the outcome of async requests to their callers
+    fn handle_event(
+        &mut self,
+        event: SwarmEvent<WakuLightNodeEvent>,
+    ) -> Option<SwarmEvent<WakuLightNodeEvent>> {
+        let event = match event {
+            SwarmEvent::Behaviour(event) => event,
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                num_established,
+                ..
+            } => {
+                if num_established.get() == 1 {
+                    self.request_metadata(&peer_id);
+                }
+                return Some(event);
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                self.peer_shards.remove(&peer_id);
+                self.lose_subscriptions(peer_id, true);
+                return Some(event);
+            }
+            event => return Some(event),
+        };
+        let event = match event {
+            WakuLightNodeEvent::Metadata(event) => self.handle_metadata(event),
+            WakuLightNodeEvent::PeerExchange(request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+            }) => {
+                self.serve_peer_exchange(peer, request, channel);
+                None
+            }
+            WakuLightNodeEvent::PeerExchange(mut event) => {
+                if let request_response::Event::Message {
+                    message: request_response::Message::Response { response, .. },
+                    ..
+                } = &mut event
+                {
+                    self.add_exchanged_peers(response);
+                }
+                self.pending.peer_exchange.handle(
+                    event,
+                    |rpc| rpc.response.ok_or(Error::MissingResponse),
+                    WakuLightNodeEvent::PeerExchange,
+                )
+            }
+            WakuLightNodeEvent::LightPush(request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+            }) => {
+                self.serve_light_push(peer, request, channel);
+                None
+            }
+            WakuLightNodeEvent::LightPushV3(request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+            }) => {
+                self.serve_light_push_v3(peer, request, channel);
+                None
+            }
+            WakuLightNodeEvent::LightPush(event) => self
+                .pending
+                .light_push
+                .handle(
+                    event,
+                    |rpc| {
+                        let response = rpc.response.ok_or(Error::MissingResponse)?;
+                        Ok(response.into_result()?)
+                    },
+                    WakuLightNodeEvent::LightPush,
+                )
+                .and_then(|event| self.track_delivery(PushVersion::V2, event)),
+            WakuLightNodeEvent::LightPushV3(event) => self
+                .pending
+                .light_push_v3
+                .handle(
+                    event,
+                    |response| Ok(response.into_result()?),
+                    WakuLightNodeEvent::LightPushV3,
+                )
+                .and_then(|event| self.track_delivery(PushVersion::V3, event)),
+            WakuLightNodeEvent::Filter(request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+            }) => {
+                self.serve_filter(peer, request, channel);
+                None
+            }
+            WakuLightNodeEvent::Filter(event) => {
+                let internal = self.track_filter_outcome(&event);
+                let event = self.pending.filter.handle(
+                    event,
+                    |response| Ok(response.into_result()?),
+                    WakuLightNodeEvent::Filter,
+                );
+                event.filter(|_| !internal)
+            }
+            WakuLightNodeEvent::Store(event) => self.pending.store.handle(
+                event,
+                |response| Ok(response.into_result()?),
+                WakuLightNodeEvent::Store,
+            ),
+            WakuLightNodeEvent::Message {
+                peer,
+                pubsub_topic,
+                message,
+                hash,
+            } => self.handle_message(peer, pubsub_topic, message, hash),
+            WakuLightNodeEvent::FilterPush(request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+            }) => {
+                // Acknowledge by closing the stream, the protocol having no response
+                let _ = self
+                    .swarm
+                    .behaviour_mut()
+                    .filter_push
+                    .send_response(channel, ());
+                self.receive_push(peer, request)
+            }
+            WakuLightNodeEvent::FilterPush(
+                request_response::Event::Message {
+                    message: request_response::Message::Response { .. },
+                    ..
+                }
+                | request_response::Event::ResponseSent { .. },
+            ) => None,
+            WakuLightNodeEvent::FilterPush(request_response::Event::OutboundFailure {
+                peer,
+                error,
+                ..
+            }) => {
+                debug!("Filter push to {} failed: {}", peer, error);
+                None
+            }
+            WakuLightNodeEvent::Relay(gossipsub::Event::Message {
+                propagation_source,
+                message_id,
+                message,
+            }) => self.validate_relayed(propagation_source, message_id, message),
+            event => Some(event),
+        };
+        event.map(SwarmEvent::Behaviour)
+    }
+
+    /// Turn a message pushed by a filter service node into a [`WakuLightNodeEvent::Message`]
+    ///
+    /// Pushes without a pubsub topic get it autosharded from the content topic. Pushes
+    /// on topics the peer doesn't hold a subscription of ours for get dropped.
+    fn receive_push(
+        &mut self,
+        peer: PeerId,
+        push: filter_push::MessagePush,
+    ) -> Option<WakuLightNodeEvent> {
+        let Some(message) = push.waku_message else {
+            debug!("Dropping filter push of {} without a message", peer);
+            return None;
+        };
+        let topics = ContentTopic::from_str(&message.content_topic).and_then(|content_topic| {
+            let pubsub_topic = match &push.pubsub_topic {
+                Some(pubsub_topic) => PubsubTopic::from_str(pubsub_topic)?,
+                None => self.autoshard(&content_topic)?,
+            };
+            Ok((pubsub_topic, content_topic))
+        });
+        let pubsub_topic = match topics {
+            Ok((pubsub_topic, content_topic))
+                if self
+                    .subscriptions
+                    .expects(&peer, &pubsub_topic, &content_topic) =>
+            {
+                push.pubsub_topic
+                    .unwrap_or_else(|| pubsub_topic.to_string())
+            }
+            _ => {
+                debug!(
+                    "Dropping unsolicited filter push of {} on {:?} {}",
+                    peer, push.pubsub_topic, message.content_topic
+                );
+                return None;
+            }
+        };
+        let hash = message_hash(&pubsub_topic, &message);
+        self.handle_message(peer, pubsub_topic, message, hash)
+    }
+
+    /// Drop messages already received and validate the timestamp of new ones
+    fn handle_message(
+        &mut self,
+        peer: PeerId,
+        pubsub_topic: String,
+        message: WakuMessage,
+        hash: MessageHash,
+    ) -> Option<WakuLightNodeEvent> {
+        if !self.seen_messages.insert(hash) {
+            return None;
+        }
+        match message.validate_timestamp(self.max_timestamp_drift) {
+            Ok(()) => Some(WakuLightNodeEvent::Message {
+                peer,
+                pubsub_topic,
+                message,
+                hash,
+            }),
+            Err(error) => Some(WakuLightNodeEvent::InvalidMessage {
+                peer,
+                message,
+                error,
+            }),
+        }
+    }
+
+    /// Validate a message received via relay, reporting the verdict to gossipsub so
+    /// only valid messages get forwarded
+    fn validate_relayed(
+        &mut self,
+        peer: PeerId,
+        message_id: gossipsub::MessageId,
+        message: gossipsub::Message,
+    ) -> Option<WakuLightNodeEvent> {
+        let pubsub_topic = message.topic.into_string();
+        let (verdict, waku_message) = self
+            .validation
+            .validate_encoded(&pubsub_topic, &message.data);
+        if let Some(relay) = self.swarm.behaviour_mut().relay.as_mut() {
+            if let Err(error) =
+                relay.report_message_validation_result(&message_id, &peer, (&verdict).into())
+            {
+                debug!("Reporting validation of {} failed: {}", message_id, error);
+            }
+        }
+        let Some(message) = waku_message else {
+            debug!(
+                "Rejected message {} relayed by {}: {:?}",
+                message_id, peer, verdict
+            );
+            return None;
+        };
+        match verdict {
+            Verdict::Accept => {
+                self.push_to_subscribers(&pubsub_topic, &message);
+                let hash = message_hash(&pubsub_topic, &message);
+                self.handle_message(peer, pubsub_topic, message, hash)
+            }
+            Verdict::Reject(reason) => Some(WakuLightNodeEvent::InvalidMessage {
+                peer,
+                message,
+                error: Error::Rejected(reason),
+            }),
+            Verdict::Ignore => None,
+        }
+    }
+
+    /// Start the metadata handshake with a newly connected peer
+    fn request_metadata(&mut self, peer: &PeerId) {
+        let request = metadata::messages::WakuMetadataRequest {
+            cluster_id: Some(self.cluster_id.into()),
+            shards: self.shards.iter().copied().map(u32::from).collect(),
+        };
+        self.swarm
+            .behaviour_mut()
+            .metadata
+            .send_request(peer, request);
+    }
+
+    /// Answer metadata requests and check the cluster of peers, disconnecting
+    /// those on a different one
+    fn handle_metadata(
+        &mut self,
+        event: request_response::Event<
+            metadata::messages::WakuMetadataRequest,
+            metadata::messages::WakuMetadataResponse,
+        >,
+    ) -> Option<WakuLightNodeEvent> {
+        let (peer, cluster_id, shards) = match event {
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+            } => {
+                // Peers on another cluster get disconnected without an answer, which the
+                // disconnection would drop before it got flushed anyway
+                if request.cluster_id == Some(self.cluster_id.into()) {
+                    let response = metadata::messages::WakuMetadataResponse {
+                        cluster_id: Some(self.cluster_id.into()),
+                        shards: self.shards.iter().copied().map(u32::from).collect(),
+                    };
+                    if self
+                        .swarm
+                        .behaviour_mut()
+                        .metadata
+                        .send_response(channel, response)
+                        .is_err()
+                    {
+                        debug!("Metadata request of {} timed out", peer);
+                    }
+                }
+                (peer, request.cluster_id, request.shards)
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { response, .. },
+            } => (peer, response.cluster_id, response.shards),
+            event => return Some(WakuLightNodeEvent::Metadata(event)),
+        };
+
+        if cluster_id != Some(self.cluster_id.into()) {
+            info!(
+                "Disconnecting {} on cluster {:?} instead of {}",
+                peer, cluster_id, self.cluster_id
+            );
+            let _ = self.swarm.disconnect_peer_id(peer);
+            return Some(WakuLightNodeEvent::WrongCluster { peer, cluster_id });
+        }
+        let shards: Vec<u16> = shards
+            .into_iter()
+            .filter_map(|shard| u16::try_from(shard).ok())
+            .collect();
+        // Both sides request metadata, the answer to ours repeats what the peer's request
+        // told unless its shards changed in between
+        if self.peer_shards.get(&peer) == Some(&shards) {
+            return None;
+        }
+        self.peer_shards.insert(peer, shards.clone());
+        Some(WakuLightNodeEvent::PeerMetadata {
+            peer,
+            cluster_id: self.cluster_id,
+            shards,
+        })
+    }
+
+    /// Decode the ENRs from a peer exchange response into the peer store, making the
+    /// addresses of new ones known to the swarm
+    ///
+    /// Peers beyond those asked for get dropped from the response.
+    fn add_exchanged_peers(&mut self, rpc: &mut peer_exchange::messages::PeerExchangeRpc) {
+        let Some(response) = &mut rpc.response else {
+            return;
+        };
+        response.peer_infos.truncate(peer_exchange::REQUESTED_PEERS);
+        for peer_info in &response.peer_infos {
+            let peer = match EnrPeer::decode(&peer_info.enr) {
+                Ok(peer) => peer,
+                Err(error) => {
+                    debug!("Skipping exchanged peer: {}", error);
+                    continue;
+                }
+            };
+            if peer.peer_id == *self.swarm.local_peer_id() {
+                continue;
+            }
+            if self.peer_store.insert(peer.clone()) {
+                for address in &peer.addresses {
+                    self.swarm.add_peer_address(peer.peer_id, address.clone());
+                }
+                self.events
+                    .push_back(SwarmEvent::Behaviour(WakuLightNodeEvent::PeerDiscovered(
+                        peer,
+                    )));
+            }
+        }
+    }
+
+    /// Answer a peer exchange query of a peer
+    fn serve_peer_exchange(
+        &mut self,
+        peer: PeerId,
+        rpc: peer_exchange::messages::PeerExchangeRpc,
+        channel: request_response::ResponseChannel<peer_exchange::messages::PeerExchangeRpc>,
+    ) {
+        let response = self.exchange_peers(peer, rpc);
+        if self
+            .swarm
+            .behaviour_mut()
+            .peer_exchange
+            .send_response(channel, response)
+            .is_err()
+        {
+            debug!("Peer exchange request of {} timed out", peer);
+        }
+    }
+
+    /// Discovered peers on our cluster for a peer exchange query, sharing a shard with
+    /// the requester if it advertised any
+    ///
+    /// Rate limited requesters get no peers, the protocol having no status codes.
+    fn exchange_peers(
+        &mut self,
+        peer: PeerId,
+        rpc: peer_exchange::messages::PeerExchangeRpc,
+    ) -> peer_exchange::messages::PeerExchangeRpc {
+        let peer_infos = if self.peer_exchange_limiter.allow(peer) {
+            let num_peers = rpc.query.map_or(0, |query| query.num_peers);
+            let count = usize::try_from(num_peers)
+                .unwrap_or(usize::MAX)
+                .min(peer_exchange::MAX_RESPONSE_PEERS);
+            let shards = self.peer_shards.get(&peer).map(Vec::as_slice);
+            self.peer_store
+                .sample(count, self.cluster_id, shards, &peer)
+                .into_iter()
+                .map(|exchanged| peer_exchange::messages::PeerInfo {
+                    enr: exchanged.enr.clone(),
+                })
+                .collect()
+        } else {
+            debug!("Peer exchange request of {} rate limited", peer);
+            vec![]
+        };
+        peer_exchange::messages::PeerExchangeRpc {
+            query: None,
+            response: Some(PeerExchangeResponse { peer_infos }),
+        }
+    }
+
+    /// Pubsub topics of the shards the node is configured with
+    pub fn pubsub_topics(&self) -> Vec<PubsubTopic> {
+        self.shards
+            .iter()
+            .map(|shard| PubsubTopic::new(self.cluster_id, *shard))
+            .collect()
+    }
+
+    /// Peers learned about via peer exchange
+    pub fn discovered_peers(&self) -> impl Iterator<Item = &EnrPeer> {
+        self.peer_store.iter()
+    }
+
+    /// Discovered peers advertising all the given capabilities
+    pub fn discovered_peers_with(
+        &self,
+        capabilities: Capabilities,
+    ) -> impl Iterator<Item = &EnrPeer> {
+        self.peer_store.with_capabilities(capabilities)
     }
 
     /// Send a peer exchange message request
-    pub fn request_peers(&mut self, peer: &PeerId) {
-        self.swarm.behaviour_mut().peer_exchange.send_request(
+    pub fn request_peers(&mut self, peer: &PeerId) -> OutboundRequestId {
+        let request_id = self.swarm.behaviour_mut().peer_exchange.send_request(
             peer,
             peer_exchange::messages::PeerExchangeRpc {
-                query: Some(peer_exchange::messages::PeerExchangeQuery { num_peers: 5 }),
+                query: Some(peer_exchange::messages::PeerExchangeQuery {
+                    num_peers: peer_exchange::REQUESTED_PEERS as u64,
+                }),
                 response: None,
             },
         );
+        self.pending.peer_exchange.insert(request_id, None);
+        request_id
     }
 
-    /// Send a Waku message via light-push
-    pub fn send_message(
+    /// Request peers via peer exchange and wait for the response
+    pub async fn peers(&mut self, peer: &PeerId) -> Result<PeerExchangeResponse, Error> {
+        let request_id = self.request_peers(peer);
+        let (sender, outcome) = oneshot::channel();
+        self.pending.peer_exchange.wait(&request_id, sender);
+        self.wait_for(outcome).await
+    }
+
+    /// Query a store node for historical messages
+    ///
+    /// The result arrives as a [`WakuLightNodeEvent::Store`] event for the returned request id.
+    /// Queries with content topics but no pubsub topic are refused locally, as store nodes would.
+    pub fn store_query(
+        &mut self,
+        peer: &PeerId,
+        query: StoreQuery,
+    ) -> Result<OutboundRequestId, Error> {
+        let rpc_request_id = new_request_id();
+        let request = query.into_request(rpc_request_id.clone())?;
+        let request_id = self.swarm.behaviour_mut().store.send_request(peer, request);
+        self.pending.store.insert(request_id, Some(rpc_request_id));
+        Ok(request_id)
+    }
+
+    /// Query a store node for historical messages and wait for the response
+    pub async fn query(
         &mut self,
         peer: &PeerId,
-        content_topic: String,
+        query: StoreQuery,
+    ) -> Result<StoreQueryResponse, Error> {
+        let request_id = self.store_query(peer, query)?;
+        let (sender, outcome) = oneshot::channel();
+        self.pending.store.wait(&request_id, sender);
+        self.wait_for(outcome).await
+    }
+
+    /// A message to publish, on the given or autosharded pubsub topic
+    fn new_message(
+        &self,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topic: ContentTopic,
         payload: Vec<u8>,
-    ) -> Result<(), Error> {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)?
-            .as_secs()
-            .try_into()?;
+        timestamp: Option<i64>,
+    ) -> Result<(PubsubTopic, WakuMessage), Error> {
+        let pubsub_topic = match pubsub_topic {
+            Some(pubsub_topic) => pubsub_topic,
+            None => self.autoshard(&content_topic)?,
+        };
+        let timestamp = match timestamp {
+            Some(timestamp) => timestamp,
+            None => message::now_nanos()?,
+        };
+        let message = WakuMessage {
+            content_topic: content_topic.to_string(),
+            payload,
+            ephemeral: Some(false),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+        Ok((pubsub_topic, message))
+    }
 
-        self.swarm.behaviour_mut().light_push.send_request(
-            peer,
-            light_push::messages::PushRpc {
-                request_id: "0".to_owned(),
-                response: None,
-                request: Some(light_push::messages::PushRequest {
-                    pubsub_topic: DEFAULT_PUBSUB_TOPIC.to_string(),
-                    message: Some(light_push::message::WakuMessage {
-                        content_topic,
-                        payload,
-                        ephemeral: Some(false),
-                        timestamp: Some(timestamp),
-                        ..Default::default()
-                    }),
-                }),
+    /// Start delivering a message via light push, retrying as the policy allows
+    fn deliver(
+        &mut self,
+        peer: &PeerId,
+        pubsub_topic: PubsubTopic,
+        message: WakuMessage,
+        reply: Option<handle::Reply<PushOutcome>>,
+    ) -> MessageHash {
+        let hash = message_hash(&pubsub_topic.to_string(), &message);
+        self.attempt_delivery(
+            *peer,
+            Delivery {
+                pubsub_topic,
+                message,
+                hash,
+                tried: vec![],
+                attempts: 0,
+                reply,
             },
         );
-        Ok(())
+        hash
+    }
+
+    /// Push a message to a light push service node, via v3 unless the node only speaks v2
+    fn attempt_delivery(&mut self, peer: PeerId, mut delivery: Delivery) {
+        delivery.attempts += 1;
+        let rpc_request_id = new_request_id();
+        let version = if self.light_push_v2_peers.contains(&peer) {
+            PushVersion::V2
+        } else {
+            PushVersion::V3
+        };
+        let request_id = match version {
+            PushVersion::V2 => {
+                let request_id = self.swarm.behaviour_mut().light_push.send_request(
+                    &peer,
+                    light_push::messages::PushRpc {
+                        request_id: rpc_request_id.clone(),
+                        response: None,
+                        request: Some(light_push::messages::PushRequest {
+                            pubsub_topic: delivery.pubsub_topic.to_string(),
+                            message: Some(delivery.message.clone()),
+                        }),
+                    },
+                );
+                self.pending
+                    .light_push
+                    .insert(request_id, Some(rpc_request_id));
+                request_id
+            }
+            PushVersion::V3 => {
+                let request_id = self.swarm.behaviour_mut().light_push_v3.send_request(
+                    &peer,
+                    light_push_v3::messages::LightpushRequest {
+                        request_id: rpc_request_id.clone(),
+                        pubsub_topic: Some(delivery.pubsub_topic.to_string()),
+                        message: Some(delivery.message.clone()),
+                    },
+                );
+                self.pending
+                    .light_push_v3
+                    .insert(request_id, Some(rpc_request_id));
+                request_id
+            }
+        };
+        self.deliveries.sent(version, request_id, peer, delivery);
+    }
+
+    /// Retry a delivery with a light push service node not tried yet, if any, or
+    /// with the one last tried
+    fn retry_delivery(&mut self, last_tried: PeerId, delivery: Delivery) {
+        let untried: Vec<PeerId> = self
+            .peer_store
+            .with_capabilities(Capabilities::LIGHT_PUSH)
+            .map(|peer| peer.peer_id)
+            .filter(|peer| !delivery.tried.contains(peer))
+            .collect();
+        let peer = untried
+            .iter()
+            .find(|peer| self.swarm.is_connected(peer))
+            .or(untried.first())
+            .copied()
+            .unwrap_or(last_tried);
+        self.attempt_delivery(peer, delivery);
+    }
+
+    /// Act on the outcome of a light push attempt, retrying failed ones and reporting
+    /// the final outcome
+    ///
+    /// Service nodes not speaking v3 get the message again via v2, without counting an attempt.
+    fn track_delivery(
+        &mut self,
+        version: PushVersion,
+        event: WakuLightNodeEvent,
+    ) -> Option<WakuLightNodeEvent> {
+        let request_id = match &event {
+            WakuLightNodeEvent::LightPush(request_response::Event::Message {
+                message: request_response::Message::Response { request_id, .. },
+                ..
+            })
+            | WakuLightNodeEvent::LightPush(request_response::Event::OutboundFailure {
+                request_id,
+                ..
+            })
+            | WakuLightNodeEvent::LightPushV3(request_response::Event::Message {
+                message: request_response::Message::Response { request_id, .. },
+                ..
+            })
+            | WakuLightNodeEvent::LightPushV3(request_response::Event::OutboundFailure {
+                request_id,
+                ..
+            })
+            | WakuLightNodeEvent::RequestFailed { request_id, .. } => *request_id,
+            _ => return Some(event),
+        };
+        if !self.deliveries.contains(version, &request_id) {
+            return Some(event);
+        }
+        let (peer, mut delivery) = self.deliveries.take(version, &request_id)?;
+        let result = match event {
+            WakuLightNodeEvent::LightPush(request_response::Event::Message {
+                message: request_response::Message::Response { response, .. },
+                ..
+            }) => response
+                .response
+                .ok_or(Error::MissingResponse)
+                .and_then(|response| Ok(response.into_result()?)),
+            WakuLightNodeEvent::LightPushV3(request_response::Event::Message {
+                message: request_response::Message::Response { response, .. },
+                ..
+            }) => response.into_result().map_err(Error::from),
+            WakuLightNodeEvent::LightPush(request_response::Event::OutboundFailure {
+                error,
+                ..
+            })
+            | WakuLightNodeEvent::LightPushV3(request_response::Event::OutboundFailure {
+                error,
+                ..
+            }) => Err(error.into()),
+            WakuLightNodeEvent::RequestFailed { error, .. } => Err(error),
+            _ => unreachable!("Matched above"),
+        };
+        match result {
+            Ok(outcome) => self.delivered(peer, delivery, Ok(outcome)),
+            Err(Error::Outbound(request_response::OutboundFailure::UnsupportedProtocols))
+                if version == PushVersion::V3 =>
+            {
+                debug!("{} doesn't speak light push v3, falling back to v2", peer);
+                self.light_push_v2_peers.insert(peer);
+                delivery.attempts -= 1;
+                self.attempt_delivery(peer, delivery);
+                None
+            }
+            Err(error) => {
+                debug!(
+                    "Light push of {:?} to {} failed: {}",
+                    delivery.hash, peer, error
+                );
+                let delivery =
+                    self.deliveries
+                        .failed(&self.light_push_retry, peer, delivery, &error)?;
+                self.delivered(peer, delivery, Err(error))
+            }
+        }
+    }
+
+    /// Answer a light push v2 request, publishing its message if accepted
+    fn serve_light_push(
+        &mut self,
+        peer: PeerId,
+        rpc: light_push::messages::PushRpc,
+        channel: request_response::ResponseChannel<light_push::messages::PushRpc>,
+    ) {
+        let request = rpc.request.unwrap_or_default();
+        let result = self.accept_push(peer, Some(request.pubsub_topic), request.message);
+        let response = light_push::messages::PushRpc::response(rpc.request_id, &result);
+        if self
+            .swarm
+            .behaviour_mut()
+            .light_push
+            .send_response(channel, response)
+            .is_err()
+        {
+            debug!("Light push request of {} timed out", peer);
+        }
+    }
+
+    /// Answer a light push v3 request, publishing its message if accepted
+    fn serve_light_push_v3(
+        &mut self,
+        peer: PeerId,
+        request: light_push_v3::messages::LightpushRequest,
+        channel: request_response::ResponseChannel<light_push_v3::messages::LightpushResponse>,
+    ) {
+        let result = self.accept_push(peer, request.pubsub_topic, request.message);
+        let response = light_push_v3::messages::LightpushResponse::new(request.request_id, &result);
+        if self
+            .swarm
+            .behaviour_mut()
+            .light_push_v3
+            .send_response(channel, response)
+            .is_err()
+        {
+            debug!("Light push request of {} timed out", peer);
+        }
+    }
+
+    /// Validate a message pushed to us and publish it, on the given or autosharded
+    /// pubsub topic
+    ///
+    /// Returns the number of peers the message got published to. Messages a validator
+    /// ignores are refused as invalid, status 420, since light push has no way to drop
+    /// them silently.
+    fn accept_push(
+        &mut self,
+        peer: PeerId,
+        pubsub_topic: Option<String>,
+        message: Option<WakuMessage>,
+    ) -> Result<u32, PushError> {
+        if !self.light_push_limiter.allow(peer) {
+            return Err(PushError::too_many_requests());
+        }
+        let message = message.ok_or(PushError::Rejected("Message is missing".to_string()))?;
+        let pubsub_topic = match pubsub_topic.filter(|topic| !topic.is_empty()) {
+            Some(pubsub_topic) => pubsub_topic,
+            None => ContentTopic::from_str(&message.content_topic)
+                .and_then(|content_topic| self.autoshard(&content_topic))
+                .map_err(|_| RejectReason::InvalidContentTopic)?
+                .to_string(),
+        };
+        match self.validation.check(&pubsub_topic, &message) {
+            Verdict::Accept => {}
+            Verdict::Reject(reason) => return Err(reason.into()),
+            Verdict::Ignore => return Err(PushError::InvalidMessage("Ignored".to_string())),
+        }
+        let pubsub_topic =
+            PubsubTopic::from_str(&pubsub_topic).map_err(|_| RejectReason::UnsupportedShard)?;
+        debug!("Publishing message pushed by {} on {}", peer, pubsub_topic);
+        let relay_peer_count = match &mut self.light_push_sink {
+            Some(sink) => sink.publish(&pubsub_topic, &message),
+            None => self.relay_pushed(&pubsub_topic, &message),
+        }?;
+        self.push_to_subscribers(&pubsub_topic.to_string(), &message);
+        Ok(relay_peer_count)
+    }
+
+    /// Answer a filter request of a light client we serve
+    fn serve_filter(
+        &mut self,
+        peer: PeerId,
+        request: filter::FilterSubscribeRequest,
+        channel: request_response::ResponseChannel<FilterSubscribeResponse>,
+    ) {
+        let response = if self.filter_limiter.allow(peer) {
+            self.subscribers.handle(peer, request)
+        } else {
+            FilterSubscribeResponse::new(
+                request.request_id,
+                Err(FilterError::TooManyRequests(
+                    "filter request rejected due to too many requests".to_string(),
+                )),
+            )
+        };
+        if self
+            .swarm
+            .behaviour_mut()
+            .filter
+            .send_response(channel, response)
+            .is_err()
+        {
+            debug!("Filter request of {} timed out", peer);
+        }
+    }
+
+    /// Push a message to the light clients subscribed to its topics with us
+    fn push_to_subscribers(&mut self, pubsub_topic: &str, message: &WakuMessage) {
+        for peer in self
+            .subscribers
+            .matching(pubsub_topic, &message.content_topic)
+        {
+            self.swarm.behaviour_mut().filter_push.send_request(
+                &peer,
+                filter_push::MessagePush {
+                    waku_message: Some(message.clone()),
+                    pubsub_topic: Some(pubsub_topic.to_string()),
+                },
+            );
+        }
+    }
+
+    /// Publish a message pushed to us via relay
+    fn relay_pushed(
+        &mut self,
+        pubsub_topic: &PubsubTopic,
+        message: &WakuMessage,
+    ) -> Result<u32, PushError> {
+        let Some(relay) = self.swarm.behaviour_mut().relay.as_mut() else {
+            return Err(PushError::ServiceUnavailable(
+                "Relay is disabled".to_string(),
+            ));
+        };
+        let topic = relay::topic(pubsub_topic);
+        let topic_hash = topic.hash();
+        let peers = relay
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&&topic_hash))
+            .count();
+        match relay.publish(topic, message.encode_to_vec()) {
+            // Already published, possibly pushed by another client
+            Ok(_) | Err(gossipsub::PublishError::Duplicate) => {
+                Ok(u32::try_from(peers).unwrap_or(u32::MAX))
+            }
+            Err(gossipsub::PublishError::InsufficientPeers) => Err(PushError::no_peers()),
+            Err(error) => Err(PushError::ServiceUnavailable(error.to_string())),
+        }
+    }
+
+    /// Report the final outcome of a delivery, to the caller waiting for it or as an event
+    fn delivered(
+        &mut self,
+        peer: PeerId,
+        delivery: Delivery,
+        result: Result<PushOutcome, Error>,
+    ) -> Option<WakuLightNodeEvent> {
+        match delivery.reply {
+            Some(reply) => {
+                let _ = reply.send(result);
+                None
+            }
+            None => Some(WakuLightNodeEvent::Delivery {
+                hash: delivery.hash,
+                peer,
+                result,
+            }),
+        }
+    }
+
+    /// Send a Waku message via light-push
+    ///
+    /// The pubsub topic is autosharded from the content topic unless given.
+    /// The timestamp is in Unix nanoseconds, the current time unless given.
+    /// Failed pushes get retried with other service nodes as the retry policy allows, the
+    /// final outcome arrives as a [`WakuLightNodeEvent::Delivery`] event for the returned hash.
+    pub fn send_message(
+        &mut self,
+        peer: &PeerId,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topic: ContentTopic,
+        payload: Vec<u8>,
+        timestamp: Option<i64>,
+    ) -> Result<MessageHash, Error> {
+        let (pubsub_topic, message) =
+            self.new_message(pubsub_topic, content_topic, payload, timestamp)?;
+        Ok(self.deliver(peer, pubsub_topic, message, None))
+    }
+
+    /// Send a Waku message via light-push and wait for the final outcome
+    pub async fn push(
+        &mut self,
+        peer: &PeerId,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topic: ContentTopic,
+        payload: Vec<u8>,
+        timestamp: Option<i64>,
+    ) -> Result<PushOutcome, Error> {
+        let (pubsub_topic, message) =
+            self.new_message(pubsub_topic, content_topic, payload, timestamp)?;
+        let (sender, outcome) = oneshot::channel();
+        self.deliver(peer, pubsub_topic, message, Some(sender));
+        self.wait_for(outcome).await
+    }
+
+    /// Pubsub topic a content topic is autosharded to on our cluster
+    pub fn autoshard(&self, content_topic: &ContentTopic) -> Result<PubsubTopic, Error> {
+        sharding::pubsub_topic_for(self.cluster_id, self.shard_count, content_topic)
+    }
+
+    /// Content topics by pubsub topic, autosharded unless one is given
+    fn filter_groups(
+        &self,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topics: Vec<ContentTopic>,
+    ) -> Result<BTreeMap<PubsubTopic, Vec<ContentTopic>>, Error> {
+        match pubsub_topic {
+            Some(pubsub_topic) => Ok([(pubsub_topic, content_topics)].into()),
+            None => {
+                sharding::group_by_pubsub_topic(self.cluster_id, self.shard_count, content_topics)
+            }
+        }
+    }
+
+    /// Send a single filter request, tracking it to validate the response and
+    /// apply it to our subscriptions
+    fn send_filter_request(&mut self, peer: &PeerId, request: FilterRequest) -> OutboundRequestId {
+        let rpc_request_id = new_request_id();
+        let request_id = self
+            .swarm
+            .behaviour_mut()
+            .filter
+            .send_request(peer, request.clone().into_rpc(rpc_request_id.clone()));
+        self.pending
+            .filter
+            .insert(request_id, Some(rpc_request_id.clone()));
+        self.subscriptions
+            .sent(request_id, *peer, rpc_request_id, request);
+        request_id
+    }
+
+    /// Ping the service nodes holding our filter subscriptions and replace lost ones
+    fn maintain_subscriptions(&mut self) {
+        for peer in self.subscriptions.peers() {
+            self.send_filter_request(&peer, FilterRequest::Ping);
+        }
+        self.top_up_subscriptions(None);
+    }
+
+    /// Subscribe with more service nodes until every wanted subscription is held by
+    /// as many as the redundancy asks for, trying `avoid` last
+    fn top_up_subscriptions(&mut self, avoid: Option<PeerId>) {
+        let candidates = self.filter_service_nodes(avoid);
+        let top_ups = self
+            .subscriptions
+            .top_ups(self.filter_redundancy, &candidates);
+        for ((peer, pubsub_topic), content_topics) in top_ups {
+            self.send_filter_request(
+                &peer,
+                FilterRequest::Subscribe {
+                    pubsub_topic,
+                    content_topics,
+                    internal: true,
+                },
+            );
+        }
+    }
+
+    /// Known filter service nodes, connected ones first and `avoid` last
+    fn filter_service_nodes(&self, avoid: Option<PeerId>) -> Vec<PeerId> {
+        let mut candidates: Vec<PeerId> = self
+            .subscriptions
+            .service_nodes()
+            .copied()
+            .chain(
+                self.peer_store
+                    .with_capabilities(Capabilities::FILTER)
+                    .map(|peer| peer.peer_id),
+            )
+            .filter(|peer| Some(*peer) != avoid)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        candidates.sort_by_key(|peer| !self.swarm.is_connected(peer));
+        candidates.extend(avoid);
+        candidates
+    }
+
+    /// Unsubscribe service nodes other than `peer` from subscriptions the application gave up on
+    fn unsubscribe_elsewhere(
+        &mut self,
+        peer: &PeerId,
+        pubsub_topic: PubsubTopic,
+        content_topics: &[ContentTopic],
+    ) {
+        for (holder, content_topics) in
+            self.subscriptions
+                .held_elsewhere(peer, &pubsub_topic, content_topics)
+        {
+            self.send_filter_request(
+                &holder,
+                FilterRequest::Unsubscribe {
+                    pubsub_topic,
+                    content_topics,
+                    internal: true,
+                },
+            );
+        }
+    }
+
+    /// Mark the subscriptions held by a service node as lost and replace them,
+    /// elsewhere if the node is unreachable
+    fn lose_subscriptions(&mut self, peer: PeerId, unreachable: bool) {
+        let lost = self.subscriptions.lose(&peer);
+        if lost.is_empty() {
+            return;
+        }
+        for (pubsub_topic, content_topics) in lost {
+            self.events.push_back(SwarmEvent::Behaviour(
+                WakuLightNodeEvent::SubscriptionLost {
+                    peer,
+                    pubsub_topic,
+                    content_topics,
+                },
+            ));
+        }
+        self.top_up_subscriptions(unreachable.then_some(peer));
+    }
+
+    /// Apply the outcome of a filter request to our subscriptions, acting on
+    /// failed pings and making new subscriptions redundant
+    ///
+    /// Returns whether the node sent the request on its own, the event being of no
+    /// interest to the application then.
+    fn track_filter_outcome(
+        &mut self,
+        event: &request_response::Event<
+            filter::messages::FilterSubscribeRequest,
+            filter::messages::FilterSubscribeResponse,
+        >,
+    ) -> bool {
+        let (peer, request_id, response) = match event {
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+            } => (*peer, request_id, Some(response)),
+            request_response::Event::OutboundFailure {
+                peer, request_id, ..
+            } => (*peer, request_id, None),
+            _ => return false,
+        };
+        let Some((request, outcomes)) = self.subscriptions.handle(request_id, response) else {
+            return false;
+        };
+        for outcome in outcomes {
+            match outcome {
+                Outcome::Wanted => self.top_up_subscriptions(None),
+                Outcome::PingFailed { unreachable } => {
+                    debug!("Filter ping of {} failed: {:?}", peer, event);
+                    // A node answering without our subscriptions is still worth resubscribing with
+                    self.lose_subscriptions(peer, unreachable);
+                }
+                Outcome::Restored {
+                    pubsub_topic,
+                    content_topics,
+                } => self.events.push_back(SwarmEvent::Behaviour(
+                    WakuLightNodeEvent::SubscriptionRestored {
+                        peer,
+                        pubsub_topic,
+                        content_topics,
+                    },
+                )),
+                Outcome::Unwanted {
+                    pubsub_topic,
+                    content_topics,
+                } => {
+                    self.send_filter_request(
+                        &peer,
+                        FilterRequest::Unsubscribe {
+                            pubsub_topic,
+                            content_topics,
+                            internal: true,
+                        },
+                    );
+                }
+            }
+        }
+        request.is_internal()
+    }
+
+    /// Wait for the responses to several filter requests
+    async fn wait_for_filter(
+        &mut self,
+        request_ids: Vec<OutboundRequestId>,
+    ) -> Result<Vec<FilterSubscribeResponse>, Error> {
+        let outcomes: Vec<_> = request_ids
+            .iter()
+            .map(|request_id| {
+                let (sender, outcome) = oneshot::channel();
+                self.pending.filter.wait(request_id, sender);
+                outcome
+            })
+            .collect();
+        let mut responses = Vec::with_capacity(outcomes.len());
+        for outcome in outcomes {
+            responses.push(self.wait_for(outcome).await?);
+        }
+        Ok(responses)
     }
 
     /// Subscribe to topic(s) using the filter protocol
-    pub fn filter_subscribe(&mut self, peer: &PeerId, content_topics: Vec<String>) {
-        self.swarm.behaviour_mut().filter.send_request(
-            peer,
-            filter::FilterSubscribeRequest {
-                pubsub_topic: Some(DEFAULT_PUBSUB_TOPIC.to_string()),
-                content_topics,
-                request_id: "0".to_string(),
-                filter_subscribe_type: FilterSubscribeType::Subscribe as i32,
-            },
-        );
+    ///
+    /// Without a pubsub topic, content topics get autosharded and one request is sent per shard.
+    /// Once accepted, the node subscribes with more service nodes as the redundancy asks for.
+    pub fn filter_subscribe(
+        &mut self,
+        peer: &PeerId,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topics: Vec<ContentTopic>,
+    ) -> Result<Vec<OutboundRequestId>, Error> {
+        let request_ids = self
+            .filter_groups(pubsub_topic, content_topics)?
+            .into_iter()
+            .map(|(pubsub_topic, content_topics)| {
+                self.send_filter_request(
+                    peer,
+                    FilterRequest::Subscribe {
+                        pubsub_topic,
+                        content_topics,
+                        internal: false,
+                    },
+                )
+            })
+            .collect();
+        Ok(request_ids)
+    }
+
+    /// Subscribe to topic(s) using the filter protocol and wait for the responses
+    pub async fn subscribe(
+        &mut self,
+        peer: &PeerId,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topics: Vec<ContentTopic>,
+    ) -> Result<Vec<FilterSubscribeResponse>, Error> {
+        let request_ids = self.filter_subscribe(peer, pubsub_topic, content_topics)?;
+        self.wait_for_filter(request_ids).await
     }
 
     /// Unsubscribe from topic(s) using the filter protocol
-    pub fn filter_unsubscribe(&mut self, peer: &PeerId, content_topics: Vec<String>) {
-        self.swarm.behaviour_mut().filter.send_request(
-            peer,
-            filter::messages::FilterSubscribeRequest {
-                pubsub_topic: Some(DEFAULT_PUBSUB_TOPIC.to_string()),
-                content_topics,
-                request_id: "0".to_string(),
-                filter_subscribe_type: FilterSubscribeType::Unsubscribe as i32,
-            },
-        );
+    ///
+    /// Without a pubsub topic, content topics get autosharded and one request is sent per shard.
+    /// Other service nodes holding the subscriptions for redundancy get unsubscribed too.
+    pub fn filter_unsubscribe(
+        &mut self,
+        peer: &PeerId,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topics: Vec<ContentTopic>,
+    ) -> Result<Vec<OutboundRequestId>, Error> {
+        let request_ids = self
+            .filter_groups(pubsub_topic, content_topics)?
+            .into_iter()
+            .map(|(pubsub_topic, content_topics)| {
+                self.unsubscribe_elsewhere(peer, pubsub_topic, &content_topics);
+                self.send_filter_request(
+                    peer,
+                    FilterRequest::Unsubscribe {
+                        pubsub_topic,
+                        content_topics,
+                        internal: false,
+                    },
+                )
+            })
+            .collect();
+        Ok(request_ids)
+    }
+
+    /// Unsubscribe from topic(s) using the filter protocol and wait for the responses
+    pub async fn unsubscribe(
+        &mut self,
+        peer: &PeerId,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topics: Vec<ContentTopic>,
+    ) -> Result<Vec<FilterSubscribeResponse>, Error> {
+        let request_ids = self.filter_unsubscribe(peer, pubsub_topic, content_topics)?;
+        self.wait_for_filter(request_ids).await
+    }
+
+    /// Unsubscribe from all topics a filter service node holds subscriptions for
+    ///
+    /// Other service nodes holding the subscriptions for redundancy get unsubscribed too.
+    pub fn filter_unsubscribe_all(&mut self, peer: &PeerId) -> OutboundRequestId {
+        for (pubsub_topic, content_topics) in self.subscriptions.held_by(peer) {
+            self.unsubscribe_elsewhere(peer, pubsub_topic, &content_topics);
+        }
+        self.send_filter_request(peer, FilterRequest::UnsubscribeAll)
+    }
+
+    /// Unsubscribe from all topics a filter service node holds subscriptions for
+    /// and wait for the response
+    pub async fn unsubscribe_all(
+        &mut self,
+        peer: &PeerId,
+    ) -> Result<FilterSubscribeResponse, Error> {
+        let request_id = self.filter_unsubscribe_all(peer);
+        let (sender, outcome) = oneshot::channel();
+        self.pending.filter.wait(&request_id, sender);
+        self.wait_for(outcome).await
+    }
+
+    /// Active filter subscriptions, those service nodes accepted
+    pub fn subscriptions(&self) -> Vec<Subscription> {
+        self.subscriptions.list()
+    }
+
+    /// The relay behaviour, if the node takes part in relay
+    fn relay(&mut self) -> Result<&mut gossipsub::Behaviour, Error> {
+        self.swarm
+            .behaviour_mut()
+            .relay
+            .as_mut()
+            .ok_or(Error::RelayDisabled)
+    }
+
+    /// Publish a Waku message via relay
+    ///
+    /// The pubsub topic is autosharded from the content topic unless given.
+    /// The timestamp is in Unix nanoseconds, the current time unless given.
+    pub fn relay_publish(
+        &mut self,
+        pubsub_topic: Option<PubsubTopic>,
+        content_topic: ContentTopic,
+        payload: Vec<u8>,
+        timestamp: Option<i64>,
+    ) -> Result<MessageHash, Error> {
+        let (pubsub_topic, message) =
+            self.new_message(pubsub_topic, content_topic, payload, timestamp)?;
+        let hash = message_hash(&pubsub_topic.to_string(), &message);
+        self.relay()?
+            .publish(relay::topic(&pubsub_topic), message.encode_to_vec())?;
+        self.push_to_subscribers(&pubsub_topic.to_string(), &message);
+        Ok(hash)
+    }
+
+    /// Subscribe to a pubsub topic via relay, its messages arriving as
+    /// [`WakuLightNodeEvent::Message`] events
+    ///
+    /// Returns whether the node wasn't subscribed already.
+    pub fn relay_subscribe(&mut self, pubsub_topic: &PubsubTopic) -> Result<bool, Error> {
+        Ok(self.relay()?.subscribe(&relay::topic(pubsub_topic))?)
+    }
+
+    /// Unsubscribe from a pubsub topic via relay
+    ///
+    /// Returns whether the node was subscribed.
+    pub fn relay_unsubscribe(&mut self, pubsub_topic: &PubsubTopic) -> Result<bool, Error> {
+        Ok(self.relay()?.unsubscribe(&relay::topic(pubsub_topic))?)
+    }
+
+    /// Number of relayed messages rejected by validation, by reason
+    ///
+    /// Rejected light push requests aren't counted, the pusher gets the reason instead.
+    pub fn rejections(&self) -> &BTreeMap<RejectReason, u64> {
+        self.validation.rejections()
     }
 }
 
+/// Ticks every `period`, the first one a period from now
+fn filter_ping(period: Duration) -> Interval {
+    let mut interval = time::interval_at(time::Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+/// A random id for a protocol RPC, letting service nodes and us match responses to requests
+fn new_request_id() -> String {
+    rand::random::<[u8; 16]>()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "WakuLightNodeEvent")]
 pub struct WakuLightNodeBehaviour {
     peer_exchange: request_response::Behaviour<peer_exchange::Codec>,
     metadata: request_response::Behaviour<metadata::Codec>,
     light_push: request_response::Behaviour<light_push::Codec>,
+    light_push_v3: request_response::Behaviour<light_push_v3::Codec>,
     filter: request_response::Behaviour<filter::Codec>,
+    filter_push: request_response::Behaviour<filter_push::Codec>,
+    store: request_response::Behaviour<store::Codec>,
+    relay: Toggle<gossipsub::Behaviour>,
 }
 
 impl WakuLightNodeBehaviour {
-    fn new() -> Self {
+    fn new(
+        relay: bool,
+        light_push_service: bool,
+        filter_service: bool,
+        peer_exchange_service: bool,
+    ) -> Self {
+        let peer_exchange_support = if peer_exchange_service {
+            request_response::ProtocolSupport::Full
+        } else {
+            request_response::ProtocolSupport::Outbound
+        };
+        let light_push_support = if light_push_service {
+            request_response::ProtocolSupport::Full
+        } else {
+            request_response::ProtocolSupport::Outbound
+        };
+        let (filter_support, filter_push_support) = if filter_service {
+            (
+                request_response::ProtocolSupport::Full,
+                request_response::ProtocolSupport::Full,
+            )
+        } else {
+            (
+                request_response::ProtocolSupport::Outbound,
+                request_response::ProtocolSupport::Inbound,
+            )
+        };
         Self {
-            peer_exchange: request_response::Behaviour::new(
+            peer_exchange: request_response::Behaviour::with_codec(
+                peer_exchange::codec(),
                 [(
                     StreamProtocol::new(peer_exchange::PROTOCOL_NAME),
-                    request_response::ProtocolSupport::Full,
+                    peer_exchange_support,
                 )],
                 request_response::Config::default(),
             ),
-            metadata: request_response::Behaviour::new(
+            metadata: request_response::Behaviour::with_codec(
+                metadata::codec(),
                 [(
                     StreamProtocol::new(metadata::PROTOCOL_NAME),
                     request_response::ProtocolSupport::Full,
                 )],
                 request_response::Config::default(),
             ),
-            light_push: request_response::Behaviour::new(
+            light_push: request_response::Behaviour::with_codec(
+                light_push::codec(),
                 [(
                     StreamProtocol::new(light_push::PROTOCOL_NAME),
-                    request_response::ProtocolSupport::Full,
+                    light_push_support.clone(),
                 )],
                 request_response::Config::default(),
             ),
-            filter: request_response::Behaviour::new(
+            light_push_v3: request_response::Behaviour::with_codec(
+                light_push_v3::codec(),
                 [(
-                    StreamProtocol::new(filter::PROTOCOL_NAME),
-                    request_response::ProtocolSupport::Full,
+                    StreamProtocol::new(light_push_v3::PROTOCOL_NAME),
+                    light_push_support,
+                )],
+                request_response::Config::default(),
+            ),
+            filter: request_response::Behaviour::with_codec(
+                filter::codec(),
+                [(StreamProtocol::new(filter::PROTOCOL_NAME), filter_support)],
+                request_response::Config::default(),
+            ),
+            filter_push: request_response::Behaviour::with_codec(
+                filter_push::codec(),
+                [(
+                    StreamProtocol::new(filter_push::PROTOCOL_NAME),
+                    filter_push_support,
+                )],
+                request_response::Config::default(),
+            ),
+            store: request_response::Behaviour::with_codec(
+                store::codec(),
+                [(
+                    StreamProtocol::new(store::PROTOCOL_NAME),
+                    request_response::ProtocolSupport::Outbound,
                 )],
                 request_response::Config::default(),
             ),
+            relay: relay.then(relay::behaviour).into(),
         }
     }
 }
@@ -186,6 +1915,32 @@ impl WakuLightNodeBehaviour {
 /// An event from one of the Waku light node protocols
 #[derive(Debug)]
 pub enum WakuLightNodeEvent {
+    /// First connection to a peer got established
+    PeerConnected(PeerId),
+    /// Last connection to a peer got closed
+    PeerDisconnected(PeerId),
+    /// A peer on our cluster completed the metadata handshake, or advertised other shards
+    ///
+    /// Emitted once per handshake although both sides exchange their metadata.
+    PeerMetadata {
+        peer: PeerId,
+        cluster_id: u16,
+        /// Shards the peer advertised
+        shards: Vec<u16>,
+    },
+    /// A peer got disconnected for advertising a different cluster, if any
+    WrongCluster {
+        peer: PeerId,
+        cluster_id: Option<u32>,
+    },
+    /// A new or updated peer learned about via peer exchange
+    PeerDiscovered(EnrPeer),
+    /// A request sent without awaiting its outcome failed validation
+    RequestFailed {
+        peer: PeerId,
+        request_id: OutboundRequestId,
+        error: Error,
+    },
     PeerExchange(
         request_response::Event<
             peer_exchange::messages::PeerExchangeRpc,
@@ -201,12 +1956,56 @@ pub enum WakuLightNodeEvent {
     LightPush(
         request_response::Event<light_push::messages::PushRpc, light_push::messages::PushRpc>,
     ),
+    LightPushV3(
+        request_response::Event<
+            light_push_v3::messages::LightpushRequest,
+            light_push_v3::messages::LightpushResponse,
+        >,
+    ),
     Filter(
         request_response::Event<
             filter::messages::FilterSubscribeRequest,
             filter::messages::FilterSubscribeResponse,
         >,
     ),
+    /// A message pushed to us by a filter service node we subscribed with, or
+    /// received via relay
+    Message {
+        peer: PeerId,
+        pubsub_topic: String,
+        message: WakuMessage,
+        hash: MessageHash,
+    },
+    /// A received message that failed validation
+    InvalidMessage {
+        peer: PeerId,
+        message: WakuMessage,
+        error: Error,
+    },
+    /// Final outcome of a message sent via light push, after any retries
+    Delivery {
+        hash: MessageHash,
+        /// The service node last tried
+        peer: PeerId,
+        result: Result<PushOutcome, Error>,
+    },
+    /// Filter subscriptions lost along with the service node holding them, being renewed
+    SubscriptionLost {
+        peer: PeerId,
+        pubsub_topic: PubsubTopic,
+        content_topics: Vec<ContentTopic>,
+    },
+    /// Lost filter subscriptions renewed with a service node
+    SubscriptionRestored {
+        peer: PeerId,
+        pubsub_topic: PubsubTopic,
+        content_topics: Vec<ContentTopic>,
+    },
+    /// Any other filter-push event, such as inbound failures
+    FilterPush(request_response::Event<filter_push::MessagePush, ()>),
+    Store(request_response::Event<store::StoreQueryRequest, store::StoreQueryResponse>),
+    /// Relay event, received messages being validated into [`Self::Message`] events
+    Relay(gossipsub::Event),
 }
 
 impl
@@ -276,6 +2075,46 @@ impl From<request_response::Event<light_push::messages::PushRpc, light_push::mes
     }
 }
 
+impl
+    From<
+        request_response::Event<
+            light_push_v3::messages::LightpushRequest,
+            light_push_v3::messages::LightpushResponse,
+        >,
+    > for WakuLightNodeEvent
+{
+    fn from(
+        event: request_response::Event<
+            light_push_v3::messages::LightpushRequest,
+            light_push_v3::messages::LightpushResponse,
+        >,
+    ) -> Self {
+        Self::LightPushV3(event)
+    }
+}
+
+impl From<request_response::Event<filter_push::MessagePush, ()>> for WakuLightNodeEvent {
+    fn from(event: request_response::Event<filter_push::MessagePush, ()>) -> Self {
+        Self::FilterPush(event)
+    }
+}
+
+impl From<request_response::Event<store::StoreQueryRequest, store::StoreQueryResponse>>
+    for WakuLightNodeEvent
+{
+    fn from(
+        event: request_response::Event<store::StoreQueryRequest, store::StoreQueryResponse>,
+    ) -> Self {
+        Self::Store(event)
+    }
+}
+
+impl From<gossipsub::Event> for WakuLightNodeEvent {
+    fn from(event: gossipsub::Event) -> Self {
+        Self::Relay(event)
+    }
+}
+
 /// Error when setting up or running a light node
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -293,4 +2132,582 @@ pub enum Error {
     SystemTime(#[from] std::time::SystemTimeError),
     #[error("Int conversion: {0}")]
     IntConversion(#[from] TryFromIntError),
+    #[error("Outbound request: {0}")]
+    Outbound(#[from] request_response::OutboundFailure),
+    #[error("Message timestamp {timestamp} is more than {max_drift:?} off local time")]
+    TimestampDrift { timestamp: i64, max_drift: Duration },
+    #[error("Invalid pubsub topic: {0}")]
+    InvalidPubsubTopic(String),
+    #[error("Invalid content topic: {0}")]
+    InvalidContentTopic(String),
+    #[error("Autosharding of content topic generation {0} is not supported")]
+    UnsupportedGeneration(u32),
+    #[error("No shards to autoshard content topics among")]
+    NoShards,
+    #[error("Invalid config: {0}")]
+    InvalidConfig(&'static str),
+    #[error("ENR: {0}")]
+    Enr(&'static str),
+    #[error("Filter: {0}")]
+    Filter(#[from] FilterError),
+    #[error("Store: {0}")]
+    Store(#[from] StoreError),
+    #[error("Invalid store query: {0}")]
+    InvalidStoreQuery(&'static str),
+    #[error("Light push: {0}")]
+    Push(#[from] PushError),
+    #[error("Rejected: {0}")]
+    Rejected(RejectReason),
+    #[error("Relay is disabled")]
+    RelayDisabled,
+    #[error("Relay subscription: {0}")]
+    RelaySubscription(#[from] gossipsub::SubscriptionError),
+    #[error("Relay publish: {0}")]
+    RelayPublish(#[from] gossipsub::PublishError),
+    #[error("Response is missing from the RPC")]
+    MissingResponse,
+    #[error("Response to request {expected} echoed request id {actual}")]
+    RequestIdMismatch { expected: String, actual: String },
+    #[error("Request dropped before its outcome was known")]
+    RequestDropped,
+    #[error("Node is not running")]
+    NodeStopped,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_store::tests::peer;
+
+    fn node(configure: impl FnOnce(&mut WakuLightNodeConfig)) -> WakuLightNode {
+        let mut config = WakuLightNodeConfig::new(None, vec![]);
+        configure(&mut config);
+        WakuLightNode::new_with_config(config).unwrap()
+    }
+
+    #[test]
+    fn rejects_zero_filter_ping_interval() {
+        let mut config = WakuLightNodeConfig::new(None, vec![]);
+        config.filter_ping_interval = Duration::ZERO;
+        assert!(matches!(
+            WakuLightNode::new_with_config(config),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn builds_outside_a_runtime() {
+        assert!(node(|_| {}).filter_ping.is_none());
+    }
+
+    /// A peer exchange response with records of distinct peers
+    fn peer_exchange_response(count: u8) -> peer_exchange::messages::PeerExchangeRpc {
+        let peer_infos = (1..=count)
+            .map(|index| {
+                let key = ::enr::k256::ecdsa::SigningKey::from_slice(&[index; 32]).unwrap();
+                let record = ::enr::Enr::builder().build(&key).unwrap();
+                peer_exchange::messages::PeerInfo {
+                    enr: rlp::encode(&record).to_vec(),
+                }
+            })
+            .collect();
+        peer_exchange::messages::PeerExchangeRpc {
+            query: None,
+            response: Some(PeerExchangeResponse { peer_infos }),
+        }
+    }
+
+    fn peer_exchange_query(num_peers: u64) -> peer_exchange::messages::PeerExchangeRpc {
+        peer_exchange::messages::PeerExchangeRpc {
+            query: Some(peer_exchange::messages::PeerExchangeQuery { num_peers }),
+            response: None,
+        }
+    }
+
+    fn exchanged(rpc: peer_exchange::messages::PeerExchangeRpc) -> usize {
+        rpc.response.unwrap().peer_infos.len()
+    }
+
+    fn push(
+        pubsub_topic: Option<&PubsubTopic>,
+        content_topic: &ContentTopic,
+    ) -> filter_push::MessagePush {
+        filter_push::MessagePush {
+            waku_message: Some(WakuMessage {
+                content_topic: content_topic.to_string(),
+                timestamp: Some(message::now_nanos().unwrap()),
+                ..Default::default()
+            }),
+            pubsub_topic: pubsub_topic.map(ToString::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn receives_pushes_of_subscribed_service_nodes_only() {
+        let mut node = node(|_| {});
+        let (service_node, stranger) = (PeerId::random(), PeerId::random());
+        let (content_topic, other) = (
+            ContentTopic::new("toychat", "2", "huilong", "proto").unwrap(),
+            ContentTopic::new("toychat", "2", "other", "proto").unwrap(),
+        );
+        let pubsub_topic = node.autoshard(&content_topic).unwrap();
+        node.filter_subscribe(&service_node, None, vec![content_topic.clone()])
+            .unwrap();
+
+        let received = |event: Option<WakuLightNodeEvent>| {
+            matches!(event, Some(WakuLightNodeEvent::Message { .. }))
+        };
+        assert!(!received(node.receive_push(
+            stranger,
+            push(Some(&pubsub_topic), &content_topic)
+        )));
+        assert!(!received(
+            node.receive_push(service_node, push(Some(&pubsub_topic), &other))
+        ));
+        assert!(!received(node.receive_push(
+            service_node,
+            push(
+                Some(&PubsubTopic::new(
+                    DEFAULT_CLUSTER_ID,
+                    pubsub_topic.shard + 1
+                )),
+                &content_topic
+            )
+        )));
+        assert!(received(node.receive_push(
+            service_node,
+            push(Some(&pubsub_topic), &content_topic)
+        )));
+        // Autosharded to the subscribed pubsub topic
+        let mut autosharded = push(None, &content_topic);
+        autosharded.waku_message.as_mut().unwrap().payload = b"autosharded".to_vec();
+        assert!(received(node.receive_push(service_node, autosharded)));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_light_push_v2_without_counting_an_attempt() {
+        let mut node = node(|_| {});
+        let service_node = PeerId::random();
+        let content_topic = ContentTopic::new("toychat", "2", "huilong", "proto").unwrap();
+        node.send_message(
+            &service_node,
+            None,
+            content_topic,
+            b"payload".to_vec(),
+            None,
+        )
+        .unwrap();
+        let [(PushVersion::V3, request_id, peer, 1)] = node.deliveries.in_flight()[..] else {
+            panic!("Not a first v3 attempt");
+        };
+        assert_eq!(peer, service_node);
+
+        let event = node.track_delivery(
+            PushVersion::V3,
+            WakuLightNodeEvent::LightPushV3(request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error: request_response::OutboundFailure::UnsupportedProtocols,
+            }),
+        );
+        assert!(event.is_none());
+        assert!(node.light_push_v2_peers.contains(&service_node));
+        let [(PushVersion::V2, _, peer, 1)] = node.deliveries.in_flight()[..] else {
+            panic!("Not a first v2 attempt");
+        };
+        assert_eq!(peer, service_node);
+
+        // Later deliveries go straight to v2
+        let content_topic = ContentTopic::new("toychat", "2", "other", "proto").unwrap();
+        node.send_message(
+            &service_node,
+            None,
+            content_topic,
+            b"payload".to_vec(),
+            None,
+        )
+        .unwrap();
+        assert!(node
+            .deliveries
+            .in_flight()
+            .iter()
+            .all(|(version, ..)| *version == PushVersion::V2));
+        assert!(node.light_push_v2_peers.contains(&service_node));
+    }
+
+    #[tokio::test]
+    async fn retries_with_the_last_tried_service_node_without_others() {
+        let mut node = node(|_| {});
+        let service_node = PeerId::random();
+        let delivery = Delivery {
+            pubsub_topic: PubsubTopic::new(DEFAULT_CLUSTER_ID, 0),
+            message: WakuMessage::default(),
+            hash: [0; 32],
+            tried: vec![service_node],
+            attempts: 1,
+            reply: None,
+        };
+        node.retry_delivery(service_node, delivery);
+        let [(_, _, peer, 2)] = node.deliveries.in_flight()[..] else {
+            panic!("Not a second attempt");
+        };
+        assert_eq!(peer, service_node);
+    }
+
+    /// Wait for a driver to act on what it was told, which must not take long
+    async fn soon<T>(future: impl std::future::Future<Output = T>) -> T {
+        time::timeout(Duration::from_secs(5), future)
+            .await
+            .expect("Driver is responsive")
+    }
+
+    #[tokio::test]
+    async fn drives_node_until_shutdown() {
+        let (handle, mut events) = node(|_| {}).spawn();
+        let other = handle.clone();
+        assert!(soon(handle.subscriptions()).await.unwrap().is_empty());
+        assert!(soon(other.rejections()).await.unwrap().is_empty());
+        soon(handle.shutdown()).await.unwrap();
+        assert!(matches!(
+            soon(other.subscriptions()).await,
+            Err(Error::NodeStopped)
+        ));
+        assert!(soon(events.recv()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn stops_once_all_handles_are_dropped() {
+        let (handle, mut events) = node(|_| {}).spawn();
+        let other = handle.clone();
+        drop(handle);
+        assert!(soon(other.subscriptions()).await.is_ok());
+        drop(other);
+        assert!(soon(events.recv()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn holds_events_back_instead_of_dropping_them() {
+        let mut node = node(|_| {});
+        let peers: Vec<PeerId> = (0..5).map(|_| PeerId::random()).collect();
+        for peer in &peers {
+            node.events
+                .push_back(SwarmEvent::Behaviour(WakuLightNodeEvent::PeerConnected(
+                    *peer,
+                )));
+        }
+        let (_commands, receiver) = mpsc::channel(1);
+        let (sender, mut events) = mpsc::channel(1);
+        tokio::spawn(node.run(receiver, sender));
+        for peer in peers {
+            assert!(matches!(
+                soon(events.recv()).await,
+                Some(WakuLightNodeEvent::PeerConnected(connected)) if connected == peer
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn exchanges_peers_up_to_the_rate_limit() {
+        let mut node = node(|config| {
+            config.peer_exchange_service = true;
+            config.peer_exchange_rate_limit = RateLimit {
+                requests: 2,
+                period: Duration::from_secs(60),
+            };
+        });
+        for _ in 0..3 {
+            node.peer_store.insert(peer(DEFAULT_CLUSTER_ID, &[0]));
+        }
+        let (requester, other) = (PeerId::random(), PeerId::random());
+        assert_eq!(
+            exchanged(node.exchange_peers(requester, peer_exchange_query(2))),
+            2
+        );
+        assert_eq!(
+            exchanged(node.exchange_peers(requester, peer_exchange_query(5))),
+            3
+        );
+        assert_eq!(
+            exchanged(node.exchange_peers(requester, peer_exchange_query(5))),
+            0
+        );
+        // Limited per requester
+        assert_eq!(
+            exchanged(node.exchange_peers(other, peer_exchange_query(5))),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn adds_only_the_exchanged_peers_asked_for() {
+        let mut node = node(|_| {});
+        let mut response = peer_exchange_response(20);
+        node.add_exchanged_peers(&mut response);
+        assert_eq!(exchanged(response), peer_exchange::REQUESTED_PEERS);
+        assert_eq!(
+            node.discovered_peers().count(),
+            peer_exchange::REQUESTED_PEERS
+        );
+    }
+
+    #[tokio::test]
+    async fn exchanges_peers_sharing_a_shard_with_the_requester() {
+        let mut node = node(|_| {});
+        let sharing = peer(DEFAULT_CLUSTER_ID, &[1]);
+        node.peer_store.insert(sharing.clone());
+        node.peer_store.insert(peer(DEFAULT_CLUSTER_ID, &[2]));
+        let requester = PeerId::random();
+        node.peer_shards.insert(requester, vec![1]);
+        assert_eq!(
+            exchanged(node.exchange_peers(requester, peer_exchange_query(5))),
+            1
+        );
+        // Light clients advertise no shards
+        node.peer_shards.insert(requester, vec![]);
+        assert_eq!(
+            exchanged(node.exchange_peers(requester, peer_exchange_query(5))),
+            2
+        );
+    }
+
+    /// A metadata response of `peer`, as if it answered our request
+    fn metadata_response(
+        node: &mut WakuLightNode,
+        peer: PeerId,
+        cluster_id: u16,
+        shards: &[u32],
+    ) -> request_response::Event<
+        metadata::messages::WakuMetadataRequest,
+        metadata::messages::WakuMetadataResponse,
+    > {
+        let request_id = node.swarm.behaviour_mut().metadata.send_request(
+            &peer,
+            metadata::messages::WakuMetadataRequest {
+                cluster_id: Some(node.cluster_id.into()),
+                shards: vec![],
+            },
+        );
+        request_response::Event::Message {
+            peer,
+            message: request_response::Message::Response {
+                request_id,
+                response: metadata::messages::WakuMetadataResponse {
+                    cluster_id: Some(cluster_id.into()),
+                    shards: shards.to_vec(),
+                },
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn emits_peer_metadata_once_unless_shards_change() {
+        let mut node = node(|_| {});
+        let peer = PeerId::random();
+
+        let event = metadata_response(&mut node, peer, DEFAULT_CLUSTER_ID, &[1]);
+        assert!(matches!(
+            node.handle_metadata(event),
+            Some(WakuLightNodeEvent::PeerMetadata { shards, .. }) if shards == [1]
+        ));
+        let event = metadata_response(&mut node, peer, DEFAULT_CLUSTER_ID, &[1]);
+        assert!(node.handle_metadata(event).is_none());
+        let event = metadata_response(&mut node, peer, DEFAULT_CLUSTER_ID, &[1, 2]);
+        assert!(matches!(
+            node.handle_metadata(event),
+            Some(WakuLightNodeEvent::PeerMetadata { shards, .. }) if shards == [1, 2]
+        ));
+        assert_eq!(node.peer_shards[&peer], [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn rejects_metadata_of_other_clusters() {
+        let mut node = node(|_| {});
+        let peer = PeerId::random();
+
+        let event = metadata_response(&mut node, peer, DEFAULT_CLUSTER_ID + 1, &[1]);
+        assert!(matches!(
+            node.handle_metadata(event),
+            Some(WakuLightNodeEvent::WrongCluster { peer: wrong, cluster_id })
+                if wrong == peer && cluster_id == Some(u32::from(DEFAULT_CLUSTER_ID) + 1)
+        ));
+        assert!(!node.peer_shards.contains_key(&peer));
+    }
+
+    /// Connect a node on `cluster_id` to one on the default cluster, driving both for a
+    /// while and returning the behaviour events and disconnections each one saw
+    async fn handshake(cluster_id: u16) -> [Vec<SwarmEvent<WakuLightNodeEvent>>; 2] {
+        let mut nodes = [node(|_| {}), node(|config| config.cluster_id = cluster_id)];
+        nodes[0]
+            .swarm
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = soon(nodes[0].next_event()).await {
+                break address;
+            }
+        };
+        nodes[1].swarm.dial(address).unwrap();
+
+        let mut seen = [vec![], vec![]];
+        let [listener, dialer] = &mut nodes;
+        let _ = time::timeout(Duration::from_secs(1), async {
+            loop {
+                let (index, event) = tokio::select! {
+                    event = listener.next_event() => (0, event),
+                    event = dialer.next_event() => (1, event),
+                };
+                if matches!(
+                    event,
+                    SwarmEvent::Behaviour(_) | SwarmEvent::ConnectionClosed { .. }
+                ) {
+                    seen[index].push(event);
+                }
+            }
+        })
+        .await;
+        seen
+    }
+
+    #[tokio::test]
+    async fn completes_handshake_on_the_same_cluster() {
+        for events in handshake(DEFAULT_CLUSTER_ID).await {
+            let metadata: Vec<_> = events
+                .iter()
+                .filter_map(|event| match event {
+                    SwarmEvent::Behaviour(WakuLightNodeEvent::PeerMetadata {
+                        cluster_id,
+                        shards,
+                        ..
+                    }) => Some((*cluster_id, shards.clone())),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(
+                metadata,
+                [(DEFAULT_CLUSTER_ID, (0..DEFAULT_SHARD_COUNT).collect())]
+            );
+            assert!(!events
+                .iter()
+                .any(|event| matches!(event, SwarmEvent::ConnectionClosed { .. })));
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnects_peers_on_another_cluster() {
+        for events in handshake(DEFAULT_CLUSTER_ID + 1).await {
+            assert!(events.iter().any(|event| matches!(
+                event,
+                SwarmEvent::Behaviour(WakuLightNodeEvent::WrongCluster { .. })
+            )));
+            assert!(!events.iter().any(|event| matches!(
+                event,
+                SwarmEvent::Behaviour(WakuLightNodeEvent::PeerMetadata { .. })
+            )));
+            assert!(events
+                .iter()
+                .any(|event| matches!(event, SwarmEvent::ConnectionClosed { .. })));
+        }
+    }
+
+    /// Records the pubsub topics of the messages published through it
+    #[derive(Clone, Default)]
+    struct RecordingSink(std::sync::Arc<std::sync::Mutex<Vec<PubsubTopic>>>);
+
+    impl PushSink for RecordingSink {
+        fn publish(
+            &mut self,
+            pubsub_topic: &PubsubTopic,
+            _message: &WakuMessage,
+        ) -> Result<u32, PushError> {
+            self.0.lock().unwrap().push(*pubsub_topic);
+            Ok(3)
+        }
+    }
+
+    fn light_push_service(
+        configure: impl FnOnce(&mut WakuLightNodeConfig),
+    ) -> (WakuLightNode, RecordingSink) {
+        let sink = RecordingSink::default();
+        let node = node(|config| {
+            config.light_push_service = true;
+            config.light_push_sink = Some(Box::new(sink.clone()));
+            configure(config);
+        });
+        (node, sink)
+    }
+
+    fn pushed_message() -> Option<WakuMessage> {
+        let content_topic = ContentTopic::new("toychat", "2", "huilong", "proto").unwrap();
+        push(None, &content_topic).waku_message
+    }
+
+    /// Status code a light push v3 response of `result` carries
+    fn status_code(result: &Result<u32, PushError>) -> u32 {
+        light_push_v3::messages::LightpushResponse::new(new_request_id(), result).status_code
+    }
+
+    #[test]
+    fn publishes_accepted_pushes_on_the_autosharded_pubsub_topic() {
+        let (mut node, sink) = light_push_service(|_| {});
+        let peer = PeerId::random();
+        let message = pushed_message().unwrap();
+        let pubsub_topic = node
+            .autoshard(&ContentTopic::from_str(&message.content_topic).unwrap())
+            .unwrap();
+
+        assert_eq!(node.accept_push(peer, None, Some(message.clone())), Ok(3));
+        assert_eq!(
+            node.accept_push(peer, Some(String::new()), Some(message)),
+            Ok(3)
+        );
+        assert_eq!(*sink.0.lock().unwrap(), [pubsub_topic, pubsub_topic]);
+    }
+
+    #[test]
+    fn refuses_pushes_beyond_the_rate_limit() {
+        let (mut node, _) = light_push_service(|config| {
+            config.light_push_rate_limit = RateLimit {
+                requests: 1,
+                period: Duration::from_secs(60),
+            };
+        });
+        let peer = PeerId::random();
+        assert!(node.accept_push(peer, None, pushed_message()).is_ok());
+
+        let result = node.accept_push(peer, None, pushed_message());
+        assert!(matches!(result, Err(PushError::TooManyRequests(_))));
+        assert_eq!(status_code(&result), 429);
+    }
+
+    #[test]
+    fn rejects_pushes_without_message() {
+        let (mut node, sink) = light_push_service(|_| {});
+        let result = node.accept_push(PeerId::random(), None, None);
+        assert!(matches!(result, Err(PushError::Rejected(_))));
+        assert_eq!(status_code(&result), 400);
+        assert!(sink.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_ignored_pushes_as_invalid() {
+        let (mut node, sink) = light_push_service(|config| {
+            config.relay_validators =
+                vec![Box::new(|_: &PubsubTopic, _: &WakuMessage| Verdict::Ignore)];
+        });
+        let result = node.accept_push(PeerId::random(), None, pushed_message());
+        assert_eq!(
+            result,
+            Err(PushError::InvalidMessage("Ignored".to_string()))
+        );
+        assert_eq!(status_code(&result), 420);
+        assert!(sink.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_pushes_with_relay_disabled() {
+        let mut node = node(|config| config.light_push_service = true);
+        let result = node.accept_push(PeerId::random(), None, pushed_message());
+        assert!(matches!(result, Err(PushError::ServiceUnavailable(_))));
+        assert_eq!(status_code(&result), 503);
+    }
 }
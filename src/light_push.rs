@@ -1,83 +1,254 @@
 //! Codec for the light push protocol
-use async_trait::async_trait;
-use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use libp2p::{request_response, StreamProtocol};
-use prost::Message;
-use std::io;
-
-const MAX_LIGHTPUSH_RPC_SIZE: u64 = 1024 * 1024 * 1024;
+use crate::{
+    codec::{Framing, ProtoCodec},
+    message::MAX_ENVELOPED_MESSAGE_SIZE,
+    pending::EchoedRequestId,
+    PubsubTopic, RejectReason, WakuMessage,
+};
 
 pub mod messages {
     include!(concat!(env!("OUT_DIR"), "/waku.lightpush.rs"));
 }
 
-pub mod message {
-    include!(concat!(env!("OUT_DIR"), "/waku.message.rs"));
-}
+use crate::message;
 
 pub const PROTOCOL_NAME: &str = "/vac/waku/lightpush/2.0.0-beta1";
 
-#[derive(Clone, Default)]
-pub struct Codec {}
-
-#[async_trait]
-impl request_response::Codec for Codec {
-    type Protocol = StreamProtocol;
-    type Request = messages::PushRpc;
-    type Response = messages::PushRpc;
-
-    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        let mut vec = Vec::with_capacity(MAX_LIGHTPUSH_RPC_SIZE as usize);
-        io.read_to_end(&mut vec).await?;
-        let request = Self::Request::decode_length_delimited(&vec[..])?;
-        Ok(request)
+pub type Codec = ProtoCodec<messages::PushRpc, messages::PushRpc>;
+
+pub fn codec() -> Codec {
+    ProtoCodec::new(
+        Framing::LengthPrefixed(MAX_ENVELOPED_MESSAGE_SIZE),
+        Framing::LengthPrefixed(MAX_ENVELOPED_MESSAGE_SIZE),
+    )
+}
+
+/// How nwaku describes rate limited requests
+const TOO_MANY_REQUESTS_INFO: &str = "too many requests";
+/// How nwaku describes messages it had no relay peers to publish to
+const NO_PEERS_INFOS: [&str; 2] = ["no peers", "not_published_to_any_peer"];
+
+/// Light push protocol versions
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PushVersion {
+    /// `/vac/waku/lightpush/2.0.0-beta1`
+    V2,
+    /// `/vac/waku/lightpush/3.0.0`
+    V3,
+}
+
+/// A light push the service node accepted, whichever protocol version it speaks
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PushOutcome {
+    pub version: PushVersion,
+    /// Number of relay peers the message got published to, only reported by v3
+    pub relay_peer_count: Option<u32>,
+    pub info: Option<String>,
+}
+
+/// A message the light push service node refused to relay
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum PushError {
+    /// The message itself got refused, another service node would refuse it too
+    #[error("Push rejected: {0}")]
+    Rejected(String),
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+    #[error("Invalid message: {0}")]
+    InvalidMessage(String),
+    #[error("Unsupported pubsub topic: {0}")]
+    UnsupportedPubsubTopic(String),
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+    /// The service node ran out of RLN proofs to attach to messages
+    #[error("Out of RLN proofs: {0}")]
+    OutOfRlnProof(String),
+    /// The service node had no relay peers to publish the message to
+    #[error("No relay peers: {0}")]
+    NoPeers(String),
+    #[error("Status {status_code}: {status_desc}")]
+    Other {
+        status_code: u32,
+        status_desc: String,
+    },
+}
+
+impl PushError {
+    /// Refusal of a rate limited request, worded as nwaku does
+    pub(crate) fn too_many_requests() -> Self {
+        Self::TooManyRequests(TOO_MANY_REQUESTS_INFO.to_string())
     }
 
-    async fn read_response<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-    ) -> io::Result<Self::Response>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        let mut vec = Vec::with_capacity(MAX_LIGHTPUSH_RPC_SIZE as usize);
-
-        io.read_to_end(&mut vec).await?;
-        let response = Self::Response::decode_length_delimited(&vec[..])?;
-        Ok(response)
+    /// Refusal of a message there were no relay peers for, worded as nwaku does
+    pub(crate) fn no_peers() -> Self {
+        Self::NoPeers(NO_PEERS_INFOS[1].to_string())
     }
 
-    async fn write_request<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-        req: Self::Request,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        let buf = req.encode_length_delimited_to_vec();
-        io.write_all(buf.as_ref()).await?;
-
-        Ok(())
+    /// The refusal as described to the client
+    pub(crate) fn description(&self) -> &str {
+        match self {
+            Self::Rejected(description)
+            | Self::PayloadTooLarge(description)
+            | Self::InvalidMessage(description)
+            | Self::UnsupportedPubsubTopic(description)
+            | Self::TooManyRequests(description)
+            | Self::ServiceUnavailable(description)
+            | Self::OutOfRlnProof(description)
+            | Self::NoPeers(description) => description,
+            Self::Other { status_desc, .. } => status_desc,
+        }
+    }
+
+    /// Whether the push may succeed later or with another service node
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::TooManyRequests(_)
+                | Self::ServiceUnavailable(_)
+                | Self::OutOfRlnProof(_)
+                | Self::NoPeers(_)
+        )
+    }
+
+    /// Light push v2 only reports refusals as free text, the kind is told by nwaku's wording
+    fn from_info(info: String) -> Self {
+        let lowercase = info.to_lowercase();
+        if lowercase.contains(TOO_MANY_REQUESTS_INFO) {
+            Self::TooManyRequests(info)
+        } else if NO_PEERS_INFOS
+            .iter()
+            .any(|no_peers| lowercase.contains(no_peers))
+        {
+            Self::NoPeers(info)
+        } else {
+            Self::Rejected(info)
+        }
+    }
+}
+
+impl From<RejectReason> for PushError {
+    fn from(reason: RejectReason) -> Self {
+        match reason {
+            RejectReason::TooLarge => Self::PayloadTooLarge(reason.to_string()),
+            RejectReason::UnsupportedShard => Self::UnsupportedPubsubTopic(reason.to_string()),
+            reason => Self::InvalidMessage(reason.to_string()),
+        }
     }
+}
 
-    async fn write_response<T>(
+/// Where a light push service node publishes the messages it accepted, instead of relay
+pub trait PushSink: Send {
+    /// Publish a validated message, returning the number of peers it got published to
+    fn publish(
         &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-        resp: Self::Response,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        let buf = resp.encode_length_delimited_to_vec();
-        io.write_all(buf.as_ref()).await?;
-
-        Ok(())
+        pubsub_topic: &PubsubTopic,
+        message: &WakuMessage,
+    ) -> Result<u32, PushError>;
+}
+
+impl messages::PushRpc {
+    /// Response to a push request, with the outcome of publishing its message
+    pub(crate) fn response(request_id: String, result: &Result<u32, PushError>) -> Self {
+        let response = match result {
+            Ok(_) => messages::PushResponse {
+                is_success: true,
+                info: None,
+            },
+            Err(error) => messages::PushResponse {
+                is_success: false,
+                info: Some(error.description().to_string()),
+            },
+        };
+        Self {
+            request_id,
+            request: None,
+            response: Some(response),
+        }
+    }
+}
+
+impl messages::PushResponse {
+    /// The outcome if the push succeeded, the typed refusal otherwise
+    pub fn into_result(self) -> Result<PushOutcome, PushError> {
+        if !self.is_success {
+            return Err(PushError::from_info(self.info.unwrap_or_default()));
+        }
+        Ok(PushOutcome {
+            version: PushVersion::V2,
+            relay_peer_count: None,
+            info: self.info,
+        })
+    }
+}
+
+impl EchoedRequestId for messages::PushRpc {
+    fn request_id(&self) -> Option<&str> {
+        Some(&self.request_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refusal(info: Option<&str>) -> PushError {
+        messages::PushResponse {
+            is_success: false,
+            info: info.map(ToString::to_string),
+        }
+        .into_result()
+        .unwrap_err()
+    }
+
+    #[test]
+    fn accepts_success() {
+        let response = messages::PushResponse {
+            is_success: true,
+            info: Some("OK".to_string()),
+        };
+        assert_eq!(
+            response.into_result(),
+            Ok(PushOutcome {
+                version: PushVersion::V2,
+                relay_peer_count: None,
+                info: Some("OK".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn tells_refusals_by_nwaku_wording() {
+        for info in [
+            "too many requests",
+            "Request rejected due to too many requests",
+            "Too Many Requests",
+        ] {
+            assert_eq!(
+                refusal(Some(info)),
+                PushError::TooManyRequests(info.to_string())
+            );
+        }
+        for info in [
+            "no peers",
+            "Failed to publish: No peers for topic",
+            "not_published_to_any_peer",
+        ] {
+            assert_eq!(refusal(Some(info)), PushError::NoPeers(info.to_string()));
+        }
+        for info in ["Invalid message", "peers are rate limited"] {
+            assert_eq!(refusal(Some(info)), PushError::Rejected(info.to_string()));
+        }
+        assert_eq!(refusal(None), PushError::Rejected(String::new()));
+    }
+
+    #[test]
+    fn words_refusals_as_nwaku() {
+        for error in [PushError::too_many_requests(), PushError::no_peers()] {
+            let rpc = messages::PushRpc::response("request".to_string(), &Err(error.clone()));
+            assert_eq!(rpc.request_id, "request");
+            assert_eq!(rpc.response.unwrap().into_result(), Err(error));
+        }
     }
 }
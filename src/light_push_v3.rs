@@ -0,0 +1,176 @@
+//! Codec for the light push protocol, version 3
+use crate::{
+    codec::{Framing, ProtoCodec},
+    light_push::{PushError, PushOutcome, PushVersion},
+    message::MAX_ENVELOPED_MESSAGE_SIZE,
+    pending::EchoedRequestId,
+};
+
+pub mod messages {
+    include!(concat!(env!("OUT_DIR"), "/waku.lightpush.v3.rs"));
+}
+
+pub const PROTOCOL_NAME: &str = "/vac/waku/lightpush/3.0.0";
+
+const STATUS_SUCCESS: u32 = 200;
+const STATUS_BAD_REQUEST: u32 = 400;
+const STATUS_PAYLOAD_TOO_LARGE: u32 = 413;
+const STATUS_INVALID_MESSAGE: u32 = 420;
+const STATUS_UNSUPPORTED_PUBSUB_TOPIC: u32 = 421;
+const STATUS_TOO_MANY_REQUESTS: u32 = 429;
+const STATUS_INTERNAL_SERVER_ERROR: u32 = 500;
+const STATUS_SERVICE_NOT_AVAILABLE: u32 = 503;
+const STATUS_OUT_OF_RLN_PROOF: u32 = 504;
+const STATUS_NO_PEERS_TO_RELAY: u32 = 505;
+
+pub type Codec = ProtoCodec<messages::LightpushRequest, messages::LightpushResponse>;
+
+pub fn codec() -> Codec {
+    ProtoCodec::new(
+        Framing::LengthPrefixed(MAX_ENVELOPED_MESSAGE_SIZE),
+        Framing::LengthPrefixed(MAX_ENVELOPED_MESSAGE_SIZE),
+    )
+}
+
+impl messages::LightpushResponse {
+    /// The outcome if the push succeeded, the typed refusal otherwise
+    pub fn into_result(self) -> Result<PushOutcome, PushError> {
+        let status_desc = self.status_desc.unwrap_or_default();
+        Err(match self.status_code {
+            STATUS_SUCCESS => {
+                return Ok(PushOutcome {
+                    version: PushVersion::V3,
+                    relay_peer_count: self.relay_peer_count,
+                    info: Some(status_desc).filter(|info| !info.is_empty()),
+                })
+            }
+            STATUS_BAD_REQUEST => PushError::Rejected(status_desc),
+            STATUS_PAYLOAD_TOO_LARGE => PushError::PayloadTooLarge(status_desc),
+            STATUS_INVALID_MESSAGE => PushError::InvalidMessage(status_desc),
+            STATUS_UNSUPPORTED_PUBSUB_TOPIC => PushError::UnsupportedPubsubTopic(status_desc),
+            STATUS_TOO_MANY_REQUESTS => PushError::TooManyRequests(status_desc),
+            STATUS_INTERNAL_SERVER_ERROR | STATUS_SERVICE_NOT_AVAILABLE => {
+                PushError::ServiceUnavailable(status_desc)
+            }
+            STATUS_OUT_OF_RLN_PROOF => PushError::OutOfRlnProof(status_desc),
+            STATUS_NO_PEERS_TO_RELAY => PushError::NoPeers(status_desc),
+            status_code => PushError::Other {
+                status_code,
+                status_desc,
+            },
+        })
+    }
+}
+
+impl messages::LightpushResponse {
+    /// Response to a push request, with the outcome of publishing its message
+    pub(crate) fn new(request_id: String, result: &Result<u32, PushError>) -> Self {
+        let (status_code, status_desc, relay_peer_count) = match result {
+            Ok(relay_peer_count) => (STATUS_SUCCESS, None, Some(*relay_peer_count)),
+            Err(error) => {
+                let status_code = match error {
+                    PushError::Rejected(_) => STATUS_BAD_REQUEST,
+                    PushError::PayloadTooLarge(_) => STATUS_PAYLOAD_TOO_LARGE,
+                    PushError::InvalidMessage(_) => STATUS_INVALID_MESSAGE,
+                    PushError::UnsupportedPubsubTopic(_) => STATUS_UNSUPPORTED_PUBSUB_TOPIC,
+                    PushError::TooManyRequests(_) => STATUS_TOO_MANY_REQUESTS,
+                    PushError::ServiceUnavailable(_) => STATUS_SERVICE_NOT_AVAILABLE,
+                    PushError::OutOfRlnProof(_) => STATUS_OUT_OF_RLN_PROOF,
+                    PushError::NoPeers(_) => STATUS_NO_PEERS_TO_RELAY,
+                    PushError::Other { status_code, .. } => *status_code,
+                };
+                (status_code, Some(error.description().to_string()), None)
+            }
+        };
+        Self {
+            request_id,
+            status_code,
+            status_desc,
+            relay_peer_count,
+        }
+    }
+}
+
+impl EchoedRequestId for messages::LightpushResponse {
+    fn request_id(&self) -> Option<&str> {
+        Some(&self.request_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESC: &str = "description";
+
+    fn response(status_code: u32) -> messages::LightpushResponse {
+        messages::LightpushResponse {
+            request_id: "request".to_string(),
+            status_code,
+            status_desc: Some(DESC.to_string()),
+            relay_peer_count: None,
+        }
+    }
+
+    /// Refusals by status code, all of them answered with the same status code
+    fn refusals() -> Vec<(u32, PushError)> {
+        let desc = DESC.to_string();
+        vec![
+            (400, PushError::Rejected(desc.clone())),
+            (413, PushError::PayloadTooLarge(desc.clone())),
+            (420, PushError::InvalidMessage(desc.clone())),
+            (421, PushError::UnsupportedPubsubTopic(desc.clone())),
+            (429, PushError::TooManyRequests(desc.clone())),
+            (503, PushError::ServiceUnavailable(desc.clone())),
+            (504, PushError::OutOfRlnProof(desc.clone())),
+            (505, PushError::NoPeers(desc.clone())),
+            (
+                599,
+                PushError::Other {
+                    status_code: 599,
+                    status_desc: desc,
+                },
+            ),
+        ]
+    }
+
+    #[test]
+    fn maps_status_codes_to_refusals() {
+        for (status_code, error) in refusals() {
+            assert_eq!(response(status_code).into_result(), Err(error));
+        }
+        assert_eq!(
+            response(500).into_result(),
+            Err(PushError::ServiceUnavailable(DESC.to_string()))
+        );
+    }
+
+    #[test]
+    fn maps_refusals_to_status_codes() {
+        for (status_code, error) in refusals() {
+            let response = messages::LightpushResponse::new("request".to_string(), &Err(error));
+            assert_eq!(response.status_code, status_code);
+            assert_eq!(response.status_desc.as_deref(), Some(DESC));
+            assert_eq!(response.relay_peer_count, None);
+        }
+    }
+
+    #[test]
+    fn maps_success() {
+        let success = messages::LightpushResponse::new("request".to_string(), &Ok(3));
+        assert_eq!(success.status_code, 200);
+        assert_eq!(success.request_id, "request");
+        assert_eq!(
+            success.into_result(),
+            Ok(PushOutcome {
+                version: PushVersion::V3,
+                relay_peer_count: Some(3),
+                info: None,
+            })
+        );
+        assert_eq!(
+            response(200).into_result().unwrap().info.as_deref(),
+            Some(DESC)
+        );
+    }
+}
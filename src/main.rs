@@ -1,17 +1,20 @@
-use futures::select;
-use libp2p::{futures::StreamExt, swarm::SwarmEvent};
 use std::str::FromStr;
 
 use clap::Parser;
 use libp2p::Multiaddr;
-use waku_oxidized::{WakuLightNode, WakuLightNodeConfig, WakuLightNodeEvent};
+use waku_oxidized::{
+    ContentTopic, PubsubTopic, WakuLightNode, WakuLightNodeConfig, WakuLightNodeEvent,
+};
 
 #[derive(Parser, Debug, Clone)]
 #[clap(version, about, long_about = None)]
 struct Cli {
     #[arg(short, long)]
     peers: Vec<String>,
-    topic: String,
+    /// Shard of the cluster to publish and subscribe on, autosharded from the topic unless given
+    #[arg(short, long)]
+    shard: Option<u16>,
+    topic: ContentTopic,
     message: String,
 }
 
@@ -26,41 +29,51 @@ async fn main() -> anyhow::Result<()> {
             .map(|peer| Multiaddr::from_str(peer).unwrap())
             .collect(),
     );
-    let mut node = WakuLightNode::new_with_config(config)?;
+    let pubsub_topic = cli
+        .shard
+        .map(|shard| PubsubTopic::new(config.cluster_id, shard));
+    let (node, mut events) = WakuLightNode::new_with_config(config)?.spawn();
 
-    loop {
-        select! {
-            swarm_event = node.swarm.next() => match swarm_event {
-                Some(SwarmEvent::Behaviour(WakuLightNodeEvent::Metadata(metadata))) => {
-                    println!("Got metadata {:?}", metadata);
-                    match metadata {
-                        libp2p::request_response::Event::Message { peer, message } => {
-                            println!("Got message from {:?}: {:?}", peer, message);
-                        }
-                        libp2p::request_response::Event::OutboundFailure { peer, request_id, error } => {
-                            println!("Outbound failure to {:?} for request {:?}: {:?}", peer, request_id, error);
-                        }
-                        libp2p::request_response::Event::InboundFailure { peer, request_id, error } => {
-                            println!("Inbound failure from {:?} for request {:?}: {:?}", peer, request_id, error);
-                        }
-                        libp2p::request_response::Event::ResponseSent { peer, request_id } => {
-                            println!("Response sent to {:?} for request {:?}", peer, request_id);
-                        }
+    while let Some(event) = events.recv().await {
+        match event {
+            WakuLightNodeEvent::PeerConnected(peer) => {
+                println!("Connection estabilished with {peer:?}");
+                let node = node.clone();
+                let cli = cli.clone();
+                tokio::spawn(async move {
+                    match node.peers(peer).await {
+                        Ok(response) => println!("Got peers {:?}", response),
+                        Err(error) => println!("Peer exchange failed: {error}"),
+                    }
+                    match node
+                        .subscribe(peer, pubsub_topic, vec![cli.topic.clone()])
+                        .await
+                    {
+                        Ok(response) => println!("Subscribed {:?}", response),
+                        Err(error) => println!("Filter subscribe failed: {error}"),
+                    }
+                    match node
+                        .send(peer, pubsub_topic, cli.topic, cli.message.into(), None)
+                        .await
+                    {
+                        Ok(response) => println!("Pushed message {:?}", response),
+                        Err(error) => println!("Light push failed: {error}"),
                     }
-                }
-                Some(SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. }) => {
-                    println!("Connection estabilished with {peer_id:?} on {endpoint:?}");
-                    node.request_peers(&peer_id);
-                    node.filter_subscribe(&peer_id, vec![cli.topic.clone()]);
-                    node.send_message(&peer_id, cli.topic.clone(), cli.message.clone().into())?;
-                }
-                None => {
-                    println!("Swarm event stream ended");
-                    break;
-                }
-                _ => {
-                    println!("Got swarm event {:?}", swarm_event);
-                }
+                });
+            }
+            WakuLightNodeEvent::Message {
+                peer,
+                pubsub_topic,
+                message,
+                ..
+            } => {
+                println!(
+                    "Got message from {:?} on {}: {:?}",
+                    peer, pubsub_topic, message
+                );
+            }
+            event => {
+                println!("Got event {:?}", event);
             }
         }
     }
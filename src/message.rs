@@ -0,0 +1,226 @@
+//! The Waku message, shared by all protocols carrying messages
+use std::{
+    collections::{HashSet, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+
+include!(concat!(env!("OUT_DIR"), "/waku.message.rs"));
+
+/// Max size of an encoded Waku message in bytes, as enforced by nwaku
+pub const MAX_WAKU_MESSAGE_SIZE: usize = 150 * 1024;
+
+/// Max size in bytes of an RPC carrying a single message, leaving room for its envelope
+pub(crate) const MAX_ENVELOPED_MESSAGE_SIZE: usize = MAX_WAKU_MESSAGE_SIZE + 64 * 1024;
+
+/// The deterministic hash of a Waku message, identifying it across the network
+pub type MessageHash = [u8; 32];
+
+/// Hash of a message published on a pubsub topic, as defined by the Waku message spec
+///
+/// `sha256(pubsub_topic || payload || content_topic || meta || timestamp)`, the timestamp
+/// being 8 bytes big endian. Absent fields count as empty, or zero for the timestamp.
+pub fn message_hash(pubsub_topic: &str, message: &WakuMessage) -> MessageHash {
+    Sha256::new()
+        .chain_update(pubsub_topic)
+        .chain_update(&message.payload)
+        .chain_update(&message.content_topic)
+        .chain_update(message.meta.as_deref().unwrap_or_default())
+        .chain_update(message.timestamp.unwrap_or_default().to_be_bytes())
+        .finalize()
+        .into()
+}
+
+/// Number of message hashes remembered to drop duplicates
+const SEEN_MESSAGES_CAPACITY: usize = 10_000;
+
+/// Hashes of the most recently received messages
+#[derive(Default)]
+pub(crate) struct SeenMessages {
+    hashes: HashSet<MessageHash>,
+    order: VecDeque<MessageHash>,
+}
+
+impl SeenMessages {
+    /// Remember a message, forgetting the oldest one past capacity
+    ///
+    /// Returns whether the message is new.
+    pub fn insert(&mut self, hash: MessageHash) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > SEEN_MESSAGES_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Current Unix time in nanoseconds, as Waku message timestamps are
+pub fn now_nanos() -> Result<i64, Error> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_nanos()
+        .try_into()?)
+}
+
+impl WakuMessage {
+    /// Check the timestamp is within `max_drift` of local time
+    ///
+    /// Messages without a timestamp are accepted, the field is optional.
+    pub fn validate_timestamp(&self, max_drift: Duration) -> Result<(), Error> {
+        self.validate_timestamp_at(now_nanos()?, max_drift)
+    }
+
+    /// Check the timestamp is within `max_drift` of `now`, in Unix nanoseconds
+    fn validate_timestamp_at(&self, now: i64, max_drift: Duration) -> Result<(), Error> {
+        let timestamp = match self.timestamp {
+            None | Some(0) => return Ok(()),
+            Some(timestamp) => timestamp,
+        };
+        let max_drift_nanos: u64 = max_drift.as_nanos().try_into()?;
+        if now.abs_diff(timestamp) > max_drift_nanos {
+            return Err(Error::TimestampDrift {
+                timestamp,
+                max_drift,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBSUB_TOPIC: &str = "/waku/2/default-waku/proto";
+    const CONTENT_TOPIC: &str = "/waku/2/default-content/proto";
+    const PAYLOAD: &[u8] = b"\x01\x02\x03\x04TEST\x05\x06\x07\x08";
+    const META: &[u8] = b"super-secret";
+    const TIMESTAMP: i64 = 0x175789bfa23f8400;
+
+    fn message(payload: &[u8], meta: Option<Vec<u8>>) -> WakuMessage {
+        WakuMessage {
+            payload: payload.to_vec(),
+            content_topic: CONTENT_TOPIC.to_string(),
+            meta,
+            timestamp: Some(TIMESTAMP),
+            ..Default::default()
+        }
+    }
+
+    fn hex(hash: MessageHash) -> String {
+        hash.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    // Test vectors from the 14/WAKU2-MESSAGE spec
+
+    #[test]
+    fn hash_with_meta() {
+        assert_eq!(
+            hex(message_hash(
+                PUBSUB_TOPIC,
+                &message(PAYLOAD, Some(META.to_vec()))
+            )),
+            "64cce733fed134e83da02b02c6f689814872b1a0ac97ea56b76095c3c72bfe05"
+        );
+    }
+
+    #[test]
+    fn hash_with_64_byte_meta() {
+        assert_eq!(
+            hex(message_hash(
+                PUBSUB_TOPIC,
+                &message(PAYLOAD, Some((0..64).collect()))
+            )),
+            "7158b6498753313368b9af8f6e0a0a05104f68f972981da42a43bc53fb0c1b27"
+        );
+    }
+
+    #[test]
+    fn hash_without_meta() {
+        assert_eq!(
+            hex(message_hash(PUBSUB_TOPIC, &message(PAYLOAD, None))),
+            "a2554498b31f5bcdfcbf7fa58ad1c2d45f0254f3f8110a85588ec3cf10720fd8"
+        );
+    }
+
+    #[test]
+    fn hash_with_empty_payload() {
+        assert_eq!(
+            hex(message_hash(
+                PUBSUB_TOPIC,
+                &message(&[], Some(META.to_vec()))
+            )),
+            "483ea950cb63f9b9d6926b262bb36194d3f40a0463ce8446228350bd44e96de4"
+        );
+    }
+
+    #[test]
+    fn hash_ignores_ephemeral() {
+        let mut ephemeral = message(PAYLOAD, None);
+        ephemeral.ephemeral = Some(true);
+        assert_eq!(
+            message_hash(PUBSUB_TOPIC, &ephemeral),
+            message_hash(PUBSUB_TOPIC, &message(PAYLOAD, None))
+        );
+    }
+
+    const MAX_DRIFT: Duration = Duration::from_secs(20);
+
+    fn timestamped(timestamp: Option<i64>) -> WakuMessage {
+        WakuMessage {
+            timestamp,
+            ..message(PAYLOAD, None)
+        }
+    }
+
+    #[test]
+    fn accepts_timestamps_within_drift() {
+        let drift = MAX_DRIFT.as_nanos() as i64;
+        for timestamp in [TIMESTAMP, TIMESTAMP - drift, TIMESTAMP + drift] {
+            assert!(timestamped(Some(timestamp))
+                .validate_timestamp_at(TIMESTAMP, MAX_DRIFT)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn rejects_timestamps_drifting_either_way() {
+        let drift = MAX_DRIFT.as_nanos() as i64;
+        for timestamp in [TIMESTAMP - drift - 1, TIMESTAMP + drift + 1] {
+            assert!(matches!(
+                timestamped(Some(timestamp)).validate_timestamp_at(TIMESTAMP, MAX_DRIFT),
+                Err(Error::TimestampDrift {
+                    timestamp: drifted,
+                    max_drift: MAX_DRIFT,
+                }) if drifted == timestamp
+            ));
+        }
+    }
+
+    #[test]
+    fn accepts_missing_timestamps() {
+        for timestamp in [None, Some(0)] {
+            assert!(timestamped(timestamp)
+                .validate_timestamp_at(TIMESTAMP, MAX_DRIFT)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn validates_against_local_time() {
+        assert!(timestamped(Some(now_nanos().unwrap()))
+            .validate_timestamp(MAX_DRIFT)
+            .is_ok());
+        assert!(timestamped(Some(TIMESTAMP))
+            .validate_timestamp(MAX_DRIFT)
+            .is_err());
+    }
+}
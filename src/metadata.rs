@@ -1,14 +1,10 @@
 //! Codec for the metadata protocol
-use async_trait::async_trait;
-use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use libp2p::{request_response, StreamProtocol};
-use prost::Message;
-use std::io;
+use crate::codec::{Framing, ProtoCodec};
 
 /// Max request size in bytes
-const REQUEST_SIZE_MAXIMUM: u64 = 1024 * 1024;
+const REQUEST_SIZE_MAXIMUM: usize = 1024 * 1024;
 /// Max response size in bytes
-const RESPONSE_SIZE_MAXIMUM: u64 = 10 * 1024 * 1024;
+const RESPONSE_SIZE_MAXIMUM: usize = 10 * 1024 * 1024;
 
 pub mod messages {
     include!(concat!(env!("OUT_DIR"), "/waku.metadata.rs"));
@@ -16,78 +12,46 @@ pub mod messages {
 
 pub const PROTOCOL_NAME: &str = "/vac/waku/metadata/1.0.0";
 
-#[derive(Clone, Default)]
-pub struct Codec {}
+pub type Codec = ProtoCodec<messages::WakuMetadataRequest, messages::WakuMetadataResponse>;
 
-#[async_trait]
-impl request_response::Codec for Codec {
-    type Protocol = StreamProtocol;
-    type Request = messages::WakuMetadataRequest;
-    type Response = messages::WakuMetadataResponse;
-
-    async fn read_request<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-    ) -> io::Result<messages::WakuMetadataRequest>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        let mut vec = Vec::new();
-
-        io.take(REQUEST_SIZE_MAXIMUM).read_to_end(&mut vec).await?;
-
-        let request = messages::WakuMetadataRequest::decode(&vec[..])?;
-        Ok(request)
-    }
-
-    async fn read_response<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-    ) -> io::Result<messages::WakuMetadataResponse>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        let mut vec = Vec::new();
+pub fn codec() -> Codec {
+    ProtoCodec::new(
+        Framing::LengthPrefixed(REQUEST_SIZE_MAXIMUM),
+        Framing::LengthPrefixed(RESPONSE_SIZE_MAXIMUM),
+    )
+}
 
-        io.take(RESPONSE_SIZE_MAXIMUM).read_to_end(&mut vec).await?;
-        let response = messages::WakuMetadataResponse::decode(&vec[..])?;
-        Ok(response)
-    }
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, io::Cursor};
+    use libp2p::{request_response::Codec as _, StreamProtocol};
 
-    async fn write_request<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-        req: Self::Request,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        let mut buf = Vec::with_capacity(req.encoded_len());
-        req.encode(&mut buf)?;
+    use super::*;
 
-        io.write_all(buf.as_ref()).await?;
+    const PROTOCOL: StreamProtocol = StreamProtocol::new(PROTOCOL_NAME);
 
-        Ok(())
+    #[test]
+    fn reads_length_prefixed_request() {
+        // Cluster 1 with shards 0 and 3 as nwaku's writeLp frames it, followed by bytes
+        // that must be left alone as the peer keeps the stream open for our response
+        let mut io = Cursor::new(vec![0x06, 0x08, 0x01, 0x12, 0x02, 0x00, 0x03, 0xff]);
+        let request = block_on(codec().read_request(&PROTOCOL, &mut io)).unwrap();
+        assert_eq!(request.cluster_id, Some(1));
+        assert_eq!(request.shards, vec![0, 3]);
+        assert_eq!(io.position(), 7);
     }
 
-    async fn write_response<T>(
-        &mut self,
-        _: &Self::Protocol,
-        io: &mut T,
-        resp: Self::Response,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        let mut buf = Vec::with_capacity(resp.encoded_len());
-        buf.reserve(resp.encoded_len());
-        resp.encode(&mut buf)?;
-
-        io.write_all(buf.as_ref()).await?;
-
-        Ok(())
+    #[test]
+    fn writes_length_prefixed_response() {
+        let mut io = Cursor::new(Vec::new());
+        let response = messages::WakuMetadataResponse {
+            cluster_id: Some(1),
+            shards: vec![0, 3],
+        };
+        block_on(codec().write_response(&PROTOCOL, &mut io, response)).unwrap();
+        assert_eq!(
+            io.into_inner(),
+            vec![0x06, 0x08, 0x01, 0x12, 0x02, 0x00, 0x03]
+        );
     }
 }
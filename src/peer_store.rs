@@ -0,0 +1,217 @@
+//! Peers the node learned about
+use libp2p::PeerId;
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, VecDeque};
+
+use crate::enr::{Capabilities, EnrPeer};
+
+/// Peers kept before the least recently updated ones get evicted
+const MAX_PEERS: usize = 1000;
+
+/// Peers discovered via peer exchange, by peer id
+pub(crate) struct PeerStore {
+    capacity: usize,
+    peers: HashMap<PeerId, EnrPeer>,
+    /// Peer ids from the least to the most recently updated
+    updates: VecDeque<PeerId>,
+}
+
+impl Default for PeerStore {
+    fn default() -> Self {
+        Self::new(MAX_PEERS)
+    }
+}
+
+impl PeerStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            peers: HashMap::new(),
+            updates: VecDeque::new(),
+        }
+    }
+
+    /// Add a peer or replace an older record of it, evicting the least recently
+    /// updated peer when full
+    ///
+    /// Returns whether the store changed.
+    pub fn insert(&mut self, peer: EnrPeer) -> bool {
+        match self.peers.get(&peer.peer_id) {
+            Some(known) if known.seq >= peer.seq => return false,
+            Some(_) => self.updates.retain(|updated| *updated != peer.peer_id),
+            None if self.peers.len() >= self.capacity => {
+                if let Some(evicted) = self.updates.pop_front() {
+                    self.peers.remove(&evicted);
+                }
+            }
+            None => {}
+        }
+        self.updates.push_back(peer.peer_id);
+        self.peers.insert(peer.peer_id, peer);
+        true
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &EnrPeer> {
+        self.peers.values()
+    }
+
+    /// Peers advertising all the given capabilities
+    pub fn with_capabilities(&self, capabilities: Capabilities) -> impl Iterator<Item = &EnrPeer> {
+        self.iter()
+            .filter(move |peer| peer.capabilities.contains(capabilities))
+    }
+
+    /// Up to `count` random peers on the cluster, sharing a shard with `shards` unless
+    /// there are none, as light clients advertise
+    pub fn sample(
+        &self,
+        count: usize,
+        cluster_id: u16,
+        shards: Option<&[u16]>,
+        exclude: &PeerId,
+    ) -> Vec<&EnrPeer> {
+        let shards = shards.filter(|shards| !shards.is_empty());
+        let compatible: Vec<&EnrPeer> =
+            self.iter()
+                .filter(|peer| peer.peer_id != *exclude)
+                .filter(|peer| match &peer.shards {
+                    Some(relay_shards) if relay_shards.cluster_id == cluster_id => shards
+                        .is_none_or(|shards| {
+                            relay_shards
+                                .shards
+                                .iter()
+                                .any(|shard| shards.contains(shard))
+                        }),
+                    _ => false,
+                })
+                .collect();
+        compatible
+            .choose_multiple(&mut rand::thread_rng(), count)
+            .copied()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use libp2p::identity::Keypair;
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::enr::RelayShards;
+
+    /// A discovered peer advertising the given shards of a cluster
+    pub(crate) fn peer(cluster_id: u16, shards: &[u16]) -> EnrPeer {
+        let public_key = Keypair::generate_secp256k1().public();
+        EnrPeer {
+            peer_id: public_key.to_peer_id(),
+            public_key,
+            addresses: vec![],
+            capabilities: Capabilities::default(),
+            shards: Some(RelayShards {
+                cluster_id,
+                shards: shards.to_vec(),
+            }),
+            seq: 1,
+            enr: vec![],
+        }
+    }
+
+    fn store(peers: &[EnrPeer]) -> PeerStore {
+        let mut store = PeerStore::default();
+        for peer in peers {
+            store.insert(peer.clone());
+        }
+        store
+    }
+
+    fn sampled(peers: Vec<&EnrPeer>) -> BTreeSet<PeerId> {
+        peers.into_iter().map(|peer| peer.peer_id).collect()
+    }
+
+    fn ids(peers: &[&EnrPeer]) -> BTreeSet<PeerId> {
+        peers.iter().map(|peer| peer.peer_id).collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_updated_peers() {
+        let (first, second, third) = (peer(1, &[0]), peer(1, &[0]), peer(1, &[0]));
+        let mut store = PeerStore::new(2);
+        assert!(store.insert(first.clone()));
+        assert!(store.insert(second.clone()));
+        assert!(!store.insert(first.clone()));
+        let mut updated = first.clone();
+        updated.seq += 1;
+        assert!(store.insert(updated));
+        assert!(store.insert(third.clone()));
+        assert_eq!(
+            store
+                .iter()
+                .map(|peer| peer.peer_id)
+                .collect::<BTreeSet<_>>(),
+            ids(&[&first, &third])
+        );
+    }
+
+    #[test]
+    fn samples_peers_on_the_cluster() {
+        let (on_cluster, elsewhere) = (peer(1, &[0]), peer(2, &[0]));
+        let mut unsharded = peer(1, &[]);
+        unsharded.shards = None;
+        let store = store(&[on_cluster.clone(), elsewhere, unsharded]);
+        let requester = PeerId::random();
+        assert_eq!(
+            sampled(store.sample(10, 1, None, &requester)),
+            ids(&[&on_cluster])
+        );
+    }
+
+    #[test]
+    fn samples_peers_sharing_a_shard() {
+        let (first, second, third) = (peer(1, &[0, 1]), peer(1, &[1, 2]), peer(1, &[3]));
+        let store = store(&[first.clone(), second.clone(), third]);
+        let requester = PeerId::random();
+        assert_eq!(
+            sampled(store.sample(10, 1, Some(&[1]), &requester)),
+            ids(&[&first, &second])
+        );
+        assert_eq!(
+            sampled(store.sample(10, 1, Some(&[2, 5]), &requester)),
+            ids(&[&second])
+        );
+        assert!(store.sample(10, 1, Some(&[5]), &requester).is_empty());
+    }
+
+    #[test]
+    fn samples_any_shard_without_shards_to_match() {
+        let (first, second) = (peer(1, &[0]), peer(1, &[7]));
+        let store = store(&[first.clone(), second.clone()]);
+        let requester = PeerId::random();
+        for shards in [None, Some(&[][..])] {
+            assert_eq!(
+                sampled(store.sample(10, 1, shards, &requester)),
+                ids(&[&first, &second])
+            );
+        }
+    }
+
+    #[test]
+    fn excludes_requester() {
+        let (requester, other) = (peer(1, &[0]), peer(1, &[0]));
+        let store = store(&[requester.clone(), other.clone()]);
+        assert_eq!(
+            sampled(store.sample(10, 1, None, &requester.peer_id)),
+            ids(&[&other])
+        );
+    }
+
+    #[test]
+    fn samples_up_to_count() {
+        let peers: Vec<EnrPeer> = (0..10).map(|_| peer(1, &[0])).collect();
+        let store = store(&peers);
+        let requester = PeerId::random();
+        assert_eq!(store.sample(3, 1, None, &requester).len(), 3);
+        assert_eq!(store.sample(20, 1, None, &requester).len(), 10);
+        assert!(store.sample(0, 1, None, &requester).is_empty());
+    }
+}
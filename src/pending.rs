@@ -0,0 +1,293 @@
+//! Bookkeeping of outbound requests awaiting a response
+use std::collections::HashMap;
+
+use libp2p::request_response::{self, OutboundRequestId};
+use tokio::sync::oneshot;
+
+use crate::{Error, WakuLightNodeEvent};
+
+/// Responses which echo the id of the request they answer
+pub(crate) trait EchoedRequestId {
+    /// The echoed id, `None` for protocols without request ids
+    fn request_id(&self) -> Option<&str>;
+}
+
+/// An outbound request in flight
+struct PendingRequest<T> {
+    /// Id the response has to echo, for protocols with request ids
+    rpc_request_id: Option<String>,
+    /// Caller waiting for the outcome, if any
+    sender: Option<oneshot::Sender<Result<T, Error>>>,
+}
+
+/// Outbound requests of a single protocol
+pub(crate) struct PendingRequests<T> {
+    requests: HashMap<OutboundRequestId, PendingRequest<T>>,
+}
+
+impl<T> Default for PendingRequests<T> {
+    fn default() -> Self {
+        Self {
+            requests: HashMap::new(),
+        }
+    }
+}
+
+impl<T> PendingRequests<T> {
+    /// Keep track of a sent request, so its response can be validated
+    pub fn insert(&mut self, request_id: OutboundRequestId, rpc_request_id: Option<String>) {
+        self.requests.insert(
+            request_id,
+            PendingRequest {
+                rpc_request_id,
+                sender: None,
+            },
+        );
+    }
+
+    /// Deliver the outcome of a tracked request to `sender` once it is known
+    pub fn wait(
+        &mut self,
+        request_id: &OutboundRequestId,
+        sender: oneshot::Sender<Result<T, Error>>,
+    ) {
+        if let Some(request) = self.requests.get_mut(request_id) {
+            request.sender = Some(sender);
+        }
+    }
+
+    /// Resolve the pending request a response or outbound failure belongs to
+    ///
+    /// Returns the event to emit, if the outcome isn't awaited by a caller.
+    pub fn handle<Req, Resp: EchoedRequestId>(
+        &mut self,
+        event: request_response::Event<Req, Resp>,
+        into_result: impl FnOnce(Resp) -> Result<T, Error>,
+        into_event: impl FnOnce(request_response::Event<Req, Resp>) -> WakuLightNodeEvent,
+    ) -> Option<WakuLightNodeEvent> {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+            } => {
+                let Some(request) = self.requests.remove(&request_id) else {
+                    return Some(into_event(request_response::Event::Message {
+                        peer,
+                        message: request_response::Message::Response {
+                            request_id,
+                            response,
+                        },
+                    }));
+                };
+                let mismatch = match (request.rpc_request_id, response.request_id()) {
+                    (Some(expected), Some(actual)) if expected != actual => {
+                        Some(Error::RequestIdMismatch {
+                            expected,
+                            actual: actual.to_string(),
+                        })
+                    }
+                    _ => None,
+                };
+                match (request.sender, mismatch) {
+                    (Some(sender), Some(error)) => {
+                        // The caller may have given up waiting, which is fine
+                        let _ = sender.send(Err(error));
+                        None
+                    }
+                    (Some(sender), None) => {
+                        let _ = sender.send(into_result(response));
+                        None
+                    }
+                    (None, Some(error)) => Some(WakuLightNodeEvent::RequestFailed {
+                        peer,
+                        request_id,
+                        error,
+                    }),
+                    (None, None) => Some(into_event(request_response::Event::Message {
+                        peer,
+                        message: request_response::Message::Response {
+                            request_id,
+                            response,
+                        },
+                    })),
+                }
+            }
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => match self
+                .requests
+                .remove(&request_id)
+                .and_then(|request| request.sender)
+            {
+                Some(sender) => {
+                    let _ = sender.send(Err(error.into()));
+                    None
+                }
+                None => Some(into_event(request_response::Event::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                })),
+            },
+            event => Some(into_event(event)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{request_response::ProtocolSupport, PeerId, StreamProtocol};
+
+    use super::*;
+    use crate::{filter, peer_exchange, FilterSubscribeResponse};
+
+    type FilterEvent =
+        request_response::Event<filter::FilterSubscribeRequest, FilterSubscribeResponse>;
+
+    /// A filter behaviour, minting the ids of sent requests
+    fn behaviour() -> request_response::Behaviour<filter::Codec> {
+        request_response::Behaviour::with_codec(
+            filter::codec(),
+            [(
+                StreamProtocol::new(filter::PROTOCOL_NAME),
+                ProtocolSupport::Outbound,
+            )],
+            request_response::Config::default(),
+        )
+    }
+
+    fn send(
+        behaviour: &mut request_response::Behaviour<filter::Codec>,
+        peer: &PeerId,
+    ) -> OutboundRequestId {
+        behaviour.send_request(peer, filter::FilterSubscribeRequest::default())
+    }
+
+    fn response(peer: PeerId, request_id: OutboundRequestId, echoed: &str) -> FilterEvent {
+        request_response::Event::Message {
+            peer,
+            message: request_response::Message::Response {
+                request_id,
+                response: FilterSubscribeResponse::new(echoed.to_string(), Ok(())),
+            },
+        }
+    }
+
+    fn handle(
+        pending: &mut PendingRequests<FilterSubscribeResponse>,
+        event: FilterEvent,
+    ) -> Option<WakuLightNodeEvent> {
+        pending.handle(event, Ok, WakuLightNodeEvent::Filter)
+    }
+
+    #[test]
+    fn resolves_matching_response() {
+        let (mut behaviour, peer) = (behaviour(), PeerId::random());
+        let mut pending = PendingRequests::default();
+
+        let awaited = send(&mut behaviour, &peer);
+        pending.insert(awaited, Some("awaited".to_string()));
+        let (sender, mut outcome) = oneshot::channel();
+        pending.wait(&awaited, sender);
+        assert!(handle(&mut pending, response(peer, awaited, "awaited")).is_none());
+        assert_eq!(outcome.try_recv().unwrap().unwrap().request_id, "awaited");
+
+        let unawaited = send(&mut behaviour, &peer);
+        pending.insert(unawaited, Some("unawaited".to_string()));
+        assert!(matches!(
+            handle(&mut pending, response(peer, unawaited, "unawaited")),
+            Some(WakuLightNodeEvent::Filter(
+                request_response::Event::Message { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn fails_mismatched_response() {
+        let (mut behaviour, peer) = (behaviour(), PeerId::random());
+        let mut pending = PendingRequests::default();
+
+        let awaited = send(&mut behaviour, &peer);
+        pending.insert(awaited, Some("awaited".to_string()));
+        let (sender, mut outcome) = oneshot::channel();
+        pending.wait(&awaited, sender);
+        assert!(handle(&mut pending, response(peer, awaited, "other")).is_none());
+        assert!(matches!(
+            outcome.try_recv().unwrap(),
+            Err(Error::RequestIdMismatch { expected, actual })
+                if expected == "awaited" && actual == "other"
+        ));
+
+        let unawaited = send(&mut behaviour, &peer);
+        pending.insert(unawaited, Some("unawaited".to_string()));
+        assert!(matches!(
+            handle(&mut pending, response(peer, unawaited, "other")),
+            Some(WakuLightNodeEvent::RequestFailed {
+                peer: failed,
+                request_id,
+                error: Error::RequestIdMismatch { .. },
+            }) if failed == peer && request_id == unawaited
+        ));
+    }
+
+    #[test]
+    fn accepts_any_response_without_echoed_ids() {
+        let (mut behaviour, peer) = (behaviour(), PeerId::random());
+        let mut pending = PendingRequests::default();
+        let request_id = send(&mut behaviour, &peer);
+        pending.insert(request_id, None);
+        let (sender, mut outcome) = oneshot::channel();
+        pending.wait(&request_id, sender);
+        let event = request_response::Event::<
+            peer_exchange::messages::PeerExchangeRpc,
+            peer_exchange::messages::PeerExchangeRpc,
+        >::Message {
+            peer,
+            message: request_response::Message::Response {
+                request_id,
+                response: Default::default(),
+            },
+        };
+        assert!(pending
+            .handle(event, Ok, WakuLightNodeEvent::PeerExchange)
+            .is_none());
+        assert!(outcome.try_recv().unwrap().is_ok());
+    }
+
+    #[test]
+    fn passes_untracked_responses_through() {
+        let (mut behaviour, peer) = (behaviour(), PeerId::random());
+        let mut pending = PendingRequests::default();
+        let request_id = send(&mut behaviour, &peer);
+        assert!(matches!(
+            handle(&mut pending, response(peer, request_id, "other")),
+            Some(WakuLightNodeEvent::Filter(_))
+        ));
+    }
+
+    #[test]
+    fn fails_awaited_request_on_outbound_failure() {
+        let (mut behaviour, peer) = (behaviour(), PeerId::random());
+        let mut pending = PendingRequests::default();
+        let request_id = send(&mut behaviour, &peer);
+        pending.insert(request_id, Some("awaited".to_string()));
+        let (sender, mut outcome) = oneshot::channel();
+        pending.wait(&request_id, sender);
+        let failure = request_response::Event::OutboundFailure {
+            peer,
+            request_id,
+            error: request_response::OutboundFailure::Timeout,
+        };
+        assert!(handle(&mut pending, failure).is_none());
+        assert!(matches!(
+            outcome.try_recv().unwrap(),
+            Err(Error::Outbound(request_response::OutboundFailure::Timeout))
+        ));
+    }
+}
@@ -0,0 +1,86 @@
+//! Per-peer limits on requests to the protocols we serve
+use std::{collections::HashMap, time::Duration};
+
+use libp2p::PeerId;
+use tokio::time::Instant;
+
+/// Requests a single peer may make per period
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+/// Requests made by every peer in the current period
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    /// Start of the peer's period, and requests made in it
+    peers: HashMap<PeerId, (Instant, u32)>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Count a request, returning whether the peer is within its limit
+    pub fn allow(&mut self, peer: PeerId) -> bool {
+        let now = Instant::now();
+        let period = self.limit.period;
+        self.peers
+            .retain(|_, (start, _)| now.duration_since(*start) < period);
+        let (_, requests) = self.peers.entry(peer).or_insert((now, 0));
+        *requests = requests.saturating_add(1);
+        *requests <= self.limit.requests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(period: Duration) -> RateLimiter {
+        RateLimiter::new(RateLimit {
+            requests: 2,
+            period,
+        })
+    }
+
+    #[test]
+    fn allows_requests_up_to_the_limit() {
+        let mut limiter = limiter(Duration::from_secs(60));
+        let peer = PeerId::random();
+        assert!(limiter.allow(peer));
+        assert!(limiter.allow(peer));
+        assert!(!limiter.allow(peer));
+        assert!(!limiter.allow(peer));
+    }
+
+    #[test]
+    fn limits_every_peer_separately() {
+        let mut limiter = limiter(Duration::from_secs(60));
+        let peer = PeerId::random();
+        assert!(limiter.allow(peer));
+        assert!(limiter.allow(peer));
+        assert!(limiter.allow(PeerId::random()));
+        assert!(!limiter.allow(peer));
+    }
+
+    #[test]
+    fn starts_a_new_period_once_it_elapsed() {
+        let period = Duration::from_millis(50);
+        let mut limiter = limiter(period);
+        let peer = PeerId::random();
+        assert!(limiter.allow(peer));
+        assert!(limiter.allow(peer));
+        assert!(!limiter.allow(peer));
+
+        std::thread::sleep(period);
+        assert!(limiter.allow(peer));
+        assert!(limiter.allow(peer));
+        assert!(!limiter.allow(peer));
+    }
+}
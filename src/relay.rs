@@ -0,0 +1,50 @@
+//! Gossipsub configured as the Waku relay protocol
+use std::time::Duration;
+
+use libp2p::gossipsub;
+use prost::Message;
+use sha2::{Digest, Sha256};
+
+use crate::{message::MAX_ENVELOPED_MESSAGE_SIZE, message_hash, PubsubTopic, WakuMessage};
+
+pub const PROTOCOL_NAME: &str = "/vac/waku/relay/2.0.0";
+
+/// A gossipsub behaviour with the parameters nwaku uses for relay
+///
+/// Messages are neither signed nor attributed, and identified by their Waku message hash.
+/// They only get forwarded once validated.
+pub fn behaviour() -> gossipsub::Behaviour {
+    let config = gossipsub::ConfigBuilder::default()
+        .protocol_id(PROTOCOL_NAME, gossipsub::Version::V1_1)
+        .mesh_n(6)
+        .mesh_n_low(4)
+        .mesh_n_high(8)
+        .gossip_lazy(6)
+        .heartbeat_interval(Duration::from_secs(1))
+        .history_length(6)
+        .history_gossip(3)
+        .fanout_ttl(Duration::from_secs(60))
+        .duplicate_cache_time(Duration::from_secs(2 * 60))
+        .max_transmit_size(MAX_ENVELOPED_MESSAGE_SIZE)
+        .validation_mode(gossipsub::ValidationMode::Anonymous)
+        .validate_messages()
+        .message_id_fn(message_id)
+        .build()
+        .expect("Valid relay config");
+    gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Anonymous, config)
+        .expect("Anonymous authenticity with anonymous validation")
+}
+
+/// The Waku message hash, or the hash of the raw data for undecodable messages
+fn message_id(message: &gossipsub::Message) -> gossipsub::MessageId {
+    match WakuMessage::decode(message.data.as_slice()) {
+        Ok(waku_message) => message_hash(message.topic.as_str(), &waku_message).to_vec(),
+        Err(_) => Sha256::digest(&message.data).to_vec(),
+    }
+    .into()
+}
+
+/// The gossipsub topic of a pubsub topic
+pub fn topic(pubsub_topic: &PubsubTopic) -> gossipsub::IdentTopic {
+    gossipsub::IdentTopic::new(pubsub_topic.to_string())
+}
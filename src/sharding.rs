@@ -0,0 +1,110 @@
+//! Autosharding: deriving the shard of a content topic
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::{
+    topic::{ContentTopic, PubsubTopic},
+    Error,
+};
+
+/// Pubsub topic a content topic is autosharded to, among `shard_count` shards of a cluster
+///
+/// The shard is the last 8 bytes of `sha256(application || version)`, as a big endian
+/// integer, modulo the shard count. Only generation zero is defined so far.
+pub fn pubsub_topic_for(
+    cluster_id: u16,
+    shard_count: u16,
+    content_topic: &ContentTopic,
+) -> Result<PubsubTopic, Error> {
+    if let Some(generation) = content_topic
+        .generation
+        .filter(|generation| *generation != 0)
+    {
+        return Err(Error::UnsupportedGeneration(generation));
+    }
+    if shard_count == 0 {
+        return Err(Error::NoShards);
+    }
+    let hash = Sha256::new()
+        .chain_update(&content_topic.application)
+        .chain_update(&content_topic.version)
+        .finalize();
+    let value = u64::from_be_bytes(hash[24..].try_into().expect("8 bytes"));
+    let shard = (value % u64::from(shard_count)) as u16;
+    Ok(PubsubTopic::new(cluster_id, shard))
+}
+
+/// Group content topics by the pubsub topic they're autosharded to
+pub fn group_by_pubsub_topic(
+    cluster_id: u16,
+    shard_count: u16,
+    content_topics: Vec<ContentTopic>,
+) -> Result<BTreeMap<PubsubTopic, Vec<ContentTopic>>, Error> {
+    let mut groups: BTreeMap<PubsubTopic, Vec<ContentTopic>> = BTreeMap::new();
+    for content_topic in content_topics {
+        let pubsub_topic = pubsub_topic_for(cluster_id, shard_count, &content_topic)?;
+        groups.entry(pubsub_topic).or_default().push(content_topic);
+    }
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLUSTER_ID: u16 = 1;
+    const SHARD_COUNT: u16 = 8;
+
+    fn content_topic(topic: &str) -> ContentTopic {
+        topic.parse().unwrap()
+    }
+
+    #[test]
+    fn autoshards_like_nwaku() {
+        let pubsub_topic = pubsub_topic_for(
+            CLUSTER_ID,
+            SHARD_COUNT,
+            &content_topic("/toychat/2/huilong/proto"),
+        )
+        .unwrap();
+        assert_eq!(pubsub_topic, PubsubTopic::new(CLUSTER_ID, 3));
+    }
+
+    #[test]
+    fn ignores_name_and_encoding() {
+        let pubsub_topic = pubsub_topic_for(
+            CLUSTER_ID,
+            SHARD_COUNT,
+            &content_topic("/toychat/2/other/rlp"),
+        )
+        .unwrap();
+        assert_eq!(pubsub_topic, PubsubTopic::new(CLUSTER_ID, 3));
+    }
+
+    #[test]
+    fn accepts_generation_zero() {
+        let pubsub_topic = pubsub_topic_for(
+            CLUSTER_ID,
+            SHARD_COUNT,
+            &content_topic("/0/toychat/2/huilong/proto"),
+        )
+        .unwrap();
+        assert_eq!(pubsub_topic, PubsubTopic::new(CLUSTER_ID, 3));
+    }
+
+    #[test]
+    fn rejects_later_generations() {
+        let result = pubsub_topic_for(
+            CLUSTER_ID,
+            SHARD_COUNT,
+            &content_topic("/1/toychat/2/huilong/proto"),
+        );
+        assert!(matches!(result, Err(Error::UnsupportedGeneration(1))));
+    }
+
+    #[test]
+    fn rejects_zero_shards() {
+        let result = pubsub_topic_for(CLUSTER_ID, 0, &content_topic("/toychat/2/huilong/proto"));
+        assert!(matches!(result, Err(Error::NoShards)));
+    }
+}
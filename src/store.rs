@@ -0,0 +1,271 @@
+//! Codec and query types for the store protocol
+use crate::{
+    codec::{Framing, ProtoCodec},
+    message::MAX_ENVELOPED_MESSAGE_SIZE,
+    pending::EchoedRequestId,
+    ContentTopic, Error, PubsubTopic,
+};
+
+/// Max request size in bytes
+const REQUEST_SIZE_MAXIMUM: usize = 1024 * 1024;
+/// Max response size in bytes, a full page of 100 messages with their keys
+const RESPONSE_SIZE_MAXIMUM: usize = 100 * MAX_ENVELOPED_MESSAGE_SIZE;
+
+pub mod messages {
+    include!(concat!(env!("OUT_DIR"), "/waku.store.v3.rs"));
+}
+
+pub const PROTOCOL_NAME: &str = "/vac/waku/store-query/3.0.0";
+
+pub use messages::*;
+
+const STATUS_OK: u32 = 200;
+const STATUS_BAD_REQUEST: u32 = 400;
+const STATUS_TOO_MANY_REQUESTS: u32 = 429;
+const STATUS_SERVICE_UNAVAILABLE: u32 = 503;
+
+/// A store query refused by the store node
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum StoreError {
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    /// Queries got rate limited
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+    #[error("Status {status_code}: {status_desc}")]
+    Other {
+        status_code: u32,
+        status_desc: String,
+    },
+}
+
+/// Order in which a store node pages through matching messages
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Direction {
+    /// Oldest messages first
+    Forward,
+    /// Newest messages first
+    #[default]
+    Backward,
+}
+
+/// A query for historical messages
+#[derive(Clone, Debug, Default)]
+pub struct StoreQuery {
+    /// Pubsub topic the messages were published on
+    pub pubsub_topic: Option<PubsubTopic>,
+    /// Content topics to match, requires `pubsub_topic` to be set
+    pub content_topics: Vec<ContentTopic>,
+    /// Inclusive lower bound of the message timestamp, in Unix nanoseconds
+    pub time_start: Option<i64>,
+    /// Inclusive upper bound of the message timestamp, in Unix nanoseconds
+    pub time_end: Option<i64>,
+    /// Cursor returned in a previous response, to fetch the following page
+    pub cursor: Option<Vec<u8>>,
+    /// Paging direction
+    pub direction: Direction,
+    /// Max number of messages per page, the store node's default if unset
+    pub page_size: Option<u64>,
+}
+
+impl StoreQuery {
+    /// The query for the page following `response`, if there is one
+    pub fn next_page(&self, response: &StoreQueryResponse) -> Option<Self> {
+        response.pagination_cursor.as_ref().map(|cursor| Self {
+            cursor: Some(cursor.clone()),
+            ..self.clone()
+        })
+    }
+
+    pub(crate) fn into_request(self, request_id: String) -> Result<StoreQueryRequest, Error> {
+        if !self.content_topics.is_empty() && self.pubsub_topic.is_none() {
+            return Err(Error::InvalidStoreQuery(
+                "content topics require a pubsub topic",
+            ));
+        }
+        Ok(StoreQueryRequest {
+            request_id,
+            include_data: true,
+            pubsub_topic: self.pubsub_topic.map(|topic| topic.to_string()),
+            content_topics: self
+                .content_topics
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            time_start: self.time_start,
+            time_end: self.time_end,
+            message_hashes: Vec::new(),
+            pagination_cursor: self.cursor,
+            pagination_forward: self.direction == Direction::Forward,
+            pagination_limit: self.page_size,
+        })
+    }
+}
+
+pub type Codec = ProtoCodec<messages::StoreQueryRequest, messages::StoreQueryResponse>;
+
+pub fn codec() -> Codec {
+    ProtoCodec::new(
+        Framing::LengthPrefixed(REQUEST_SIZE_MAXIMUM),
+        Framing::LengthPrefixed(RESPONSE_SIZE_MAXIMUM),
+    )
+}
+
+impl StoreQueryResponse {
+    /// The response if its status code is a success, the typed refusal otherwise
+    ///
+    /// A response without a status code is a refusal too, its messages can't be trusted
+    /// to be complete.
+    pub fn into_result(self) -> Result<Self, StoreError> {
+        let status_desc = self.status_desc.clone().unwrap_or_default();
+        Err(match self.status_code.unwrap_or_default() {
+            STATUS_OK => return Ok(self),
+            STATUS_BAD_REQUEST => StoreError::BadRequest(status_desc),
+            STATUS_TOO_MANY_REQUESTS => StoreError::TooManyRequests(status_desc),
+            STATUS_SERVICE_UNAVAILABLE => StoreError::ServiceUnavailable(status_desc),
+            status_code => StoreError::Other {
+                status_code,
+                status_desc,
+            },
+        })
+    }
+}
+
+impl EchoedRequestId for messages::StoreQueryResponse {
+    fn request_id(&self) -> Option<&str> {
+        Some(&self.request_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST_ID: &str = "request";
+
+    fn query() -> StoreQuery {
+        StoreQuery {
+            pubsub_topic: Some(PubsubTopic::new(1, 0)),
+            content_topics: vec![ContentTopic::new("toychat", "2", "huilong", "proto").unwrap()],
+            ..Default::default()
+        }
+    }
+
+    fn response(status_code: Option<u32>) -> StoreQueryResponse {
+        StoreQueryResponse {
+            request_id: REQUEST_ID.to_string(),
+            status_code,
+            status_desc: Some("description".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn requires_pubsub_topic_for_content_topics() {
+        let query = StoreQuery {
+            pubsub_topic: None,
+            ..query()
+        };
+        assert!(matches!(
+            query.into_request(REQUEST_ID.to_string()),
+            Err(Error::InvalidStoreQuery(_))
+        ));
+        assert!(StoreQuery::default()
+            .into_request(REQUEST_ID.to_string())
+            .is_ok());
+    }
+
+    #[test]
+    fn maps_query_to_request() {
+        let request = StoreQuery {
+            time_start: Some(1),
+            time_end: Some(2),
+            cursor: Some(vec![1, 2, 3]),
+            page_size: Some(20),
+            ..query()
+        }
+        .into_request(REQUEST_ID.to_string())
+        .unwrap();
+        assert_eq!(request.request_id, REQUEST_ID);
+        assert!(request.include_data);
+        assert_eq!(request.pubsub_topic.as_deref(), Some("/waku/2/rs/1/0"));
+        assert_eq!(request.content_topics, ["/toychat/2/huilong/proto"]);
+        assert_eq!((request.time_start, request.time_end), (Some(1), Some(2)));
+        assert_eq!(request.pagination_cursor, Some(vec![1, 2, 3]));
+        assert_eq!(request.pagination_limit, Some(20));
+        assert!(request.message_hashes.is_empty());
+    }
+
+    #[test]
+    fn maps_direction() {
+        let forward = |direction| {
+            StoreQuery {
+                direction,
+                ..query()
+            }
+            .into_request(REQUEST_ID.to_string())
+            .unwrap()
+            .pagination_forward
+        };
+        assert!(forward(Direction::Forward));
+        assert!(!forward(Direction::Backward));
+        assert!(!forward(Direction::default()));
+    }
+
+    #[test]
+    fn pages_from_response_cursor() {
+        let query = query();
+        assert!(query.next_page(&response(Some(STATUS_OK))).is_none());
+
+        let next = query
+            .next_page(&StoreQueryResponse {
+                pagination_cursor: Some(vec![4, 5]),
+                ..response(Some(STATUS_OK))
+            })
+            .unwrap();
+        assert_eq!(next.cursor, Some(vec![4, 5]));
+        assert_eq!(next.pubsub_topic, query.pubsub_topic);
+        assert_eq!(next.content_topics, query.content_topics);
+    }
+
+    #[test]
+    fn accepts_success() {
+        assert_eq!(
+            response(Some(STATUS_OK)).into_result(),
+            Ok(response(Some(STATUS_OK)))
+        );
+    }
+
+    #[test]
+    fn maps_status_codes_to_refusals() {
+        let error = |status_code| response(status_code).into_result().unwrap_err();
+        assert_eq!(
+            error(Some(STATUS_BAD_REQUEST)),
+            StoreError::BadRequest("description".to_string())
+        );
+        assert_eq!(
+            error(Some(STATUS_TOO_MANY_REQUESTS)),
+            StoreError::TooManyRequests("description".to_string())
+        );
+        assert_eq!(
+            error(Some(STATUS_SERVICE_UNAVAILABLE)),
+            StoreError::ServiceUnavailable("description".to_string())
+        );
+        assert_eq!(
+            error(Some(404)),
+            StoreError::Other {
+                status_code: 404,
+                status_desc: "description".to_string()
+            }
+        );
+        assert_eq!(
+            error(None),
+            StoreError::Other {
+                status_code: 0,
+                status_desc: "description".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,473 @@
+//! Filter subscriptions other nodes hold with us, when serving filter
+use libp2p::PeerId;
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use crate::{
+    filter::{
+        filter_subscribe_request::FilterSubscribeType, FilterSubscribeRequest,
+        FilterSubscribeResponse,
+    },
+    FilterError,
+};
+
+/// Content topics a single request may subscribe to or unsubscribe from, as nwaku allows
+const MAX_CONTENT_TOPICS_PER_REQUEST: usize = 100;
+
+/// How many subscriptions the filter service holds, and for how long
+#[derive(Clone, Debug)]
+pub struct SubscriberLimits {
+    /// Peers with subscriptions
+    pub max_subscribers: usize,
+    /// Pubsub and content topic pairs per subscriber
+    pub max_criteria: usize,
+    /// How long subscriptions are kept without a request from the subscriber
+    pub timeout: Duration,
+}
+
+impl Default for SubscriberLimits {
+    fn default() -> Self {
+        Self {
+            max_subscribers: 1000,
+            max_criteria: 1000,
+            timeout: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Pubsub and content topic pair a subscriber is interested in
+type Criterion = (String, String);
+
+/// A peer subscribed with us
+struct Subscriber {
+    criteria: BTreeSet<Criterion>,
+    /// Time of the last request, keeping the subscriptions alive
+    last_seen: Instant,
+}
+
+/// The subscription table of the filter service
+pub(crate) struct Subscribers {
+    limits: SubscriberLimits,
+    peers: HashMap<PeerId, Subscriber>,
+}
+
+impl Subscribers {
+    pub fn new(limits: SubscriberLimits) -> Self {
+        Self {
+            limits,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Apply a filter request of a peer to its subscriptions
+    pub fn handle(
+        &mut self,
+        peer: PeerId,
+        request: FilterSubscribeRequest,
+    ) -> FilterSubscribeResponse {
+        self.expire();
+        let result = match FilterSubscribeType::try_from(request.filter_subscribe_type) {
+            Ok(FilterSubscribeType::SubscriberPing) => self.ping(peer),
+            Ok(FilterSubscribeType::Subscribe) => {
+                criteria(request.pubsub_topic, request.content_topics)
+                    .and_then(|criteria| self.subscribe(peer, criteria))
+            }
+            Ok(FilterSubscribeType::Unsubscribe) => {
+                criteria(request.pubsub_topic, request.content_topics)
+                    .and_then(|criteria| self.unsubscribe(peer, criteria))
+            }
+            Ok(FilterSubscribeType::UnsubscribeAll) => self.unsubscribe_all(peer),
+            Err(_) => Err(FilterError::BadRequest(format!(
+                "unknown filter subscribe type: {}",
+                request.filter_subscribe_type
+            ))),
+        };
+        FilterSubscribeResponse::new(request.request_id, result)
+    }
+
+    /// Subscribers interested in a message with the given topics
+    pub fn matching(&mut self, pubsub_topic: &str, content_topic: &str) -> Vec<PeerId> {
+        self.expire();
+        let criterion = (pubsub_topic.to_string(), content_topic.to_string());
+        self.peers
+            .iter()
+            .filter(|(_, subscriber)| subscriber.criteria.contains(&criterion))
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    fn ping(&mut self, peer: PeerId) -> Result<(), FilterError> {
+        let subscriber = self.peers.get_mut(&peer).ok_or_else(not_found)?;
+        subscriber.last_seen = Instant::now();
+        Ok(())
+    }
+
+    fn subscribe(&mut self, peer: PeerId, criteria: Vec<Criterion>) -> Result<(), FilterError> {
+        if !self.peers.contains_key(&peer) && self.peers.len() >= self.limits.max_subscribers {
+            return Err(FilterError::TooManySubscriptions(
+                "node has reached maximum number of subscriptions".to_string(),
+            ));
+        }
+        let subscriber = self.peers.entry(peer).or_insert_with(|| Subscriber {
+            criteria: BTreeSet::new(),
+            last_seen: Instant::now(),
+        });
+        subscriber.last_seen = Instant::now();
+        let new = criteria
+            .iter()
+            .filter(|criterion| !subscriber.criteria.contains(criterion))
+            .count();
+        if subscriber.criteria.len() + new > self.limits.max_criteria {
+            if subscriber.criteria.is_empty() {
+                self.peers.remove(&peer);
+            }
+            return Err(FilterError::TooManySubscriptions(
+                "peer has reached maximum number of filter criteria".to_string(),
+            ));
+        }
+        subscriber.criteria.extend(criteria);
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, peer: PeerId, criteria: Vec<Criterion>) -> Result<(), FilterError> {
+        let subscriber = self.peers.get_mut(&peer).ok_or_else(not_found)?;
+        subscriber.last_seen = Instant::now();
+        let before = subscriber.criteria.len();
+        for criterion in &criteria {
+            subscriber.criteria.remove(criterion);
+        }
+        let removed = before != subscriber.criteria.len();
+        if subscriber.criteria.is_empty() {
+            self.peers.remove(&peer);
+        }
+        if removed {
+            Ok(())
+        } else {
+            Err(not_found())
+        }
+    }
+
+    fn unsubscribe_all(&mut self, peer: PeerId) -> Result<(), FilterError> {
+        self.peers.remove(&peer).map(|_| ()).ok_or_else(not_found)
+    }
+
+    /// Drop the subscriptions of peers that went quiet for longer than the timeout
+    fn expire(&mut self) {
+        let timeout = self.limits.timeout;
+        self.peers
+            .retain(|_, subscriber| subscriber.last_seen.elapsed() < timeout);
+    }
+}
+
+/// The criteria of a subscribe or unsubscribe request
+fn criteria(
+    pubsub_topic: Option<String>,
+    content_topics: Vec<String>,
+) -> Result<Vec<Criterion>, FilterError> {
+    let Some(pubsub_topic) = pubsub_topic.filter(|_| !content_topics.is_empty()) else {
+        return Err(FilterError::BadRequest(
+            "pubsub topic and content topics must be specified".to_string(),
+        ));
+    };
+    if content_topics.len() > MAX_CONTENT_TOPICS_PER_REQUEST {
+        return Err(FilterError::BadRequest(format!(
+            "exceeds maximum content topics: {MAX_CONTENT_TOPICS_PER_REQUEST}"
+        )));
+    }
+    Ok(content_topics
+        .into_iter()
+        .map(|content_topic| (pubsub_topic.clone(), content_topic))
+        .collect())
+}
+
+fn not_found() -> FilterError {
+    FilterError::NotFound("peer has no subscriptions".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBSUB_TOPIC: &str = "/waku/2/rs/1/0";
+
+    fn request(
+        filter_subscribe_type: FilterSubscribeType,
+        content_topics: &[&str],
+    ) -> FilterSubscribeRequest {
+        FilterSubscribeRequest {
+            request_id: "request".to_string(),
+            filter_subscribe_type: filter_subscribe_type as i32,
+            pubsub_topic: Some(PUBSUB_TOPIC.to_string()),
+            content_topics: content_topics.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    fn status(
+        subscribers: &mut Subscribers,
+        peer: PeerId,
+        filter_subscribe_type: FilterSubscribeType,
+        content_topics: &[&str],
+    ) -> u32 {
+        subscribers
+            .handle(peer, request(filter_subscribe_type, content_topics))
+            .status_code
+    }
+
+    fn limits(max_subscribers: usize, max_criteria: usize) -> SubscriberLimits {
+        SubscriberLimits {
+            max_subscribers,
+            max_criteria,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn subscribes_and_unsubscribes() {
+        let mut subscribers = Subscribers::new(SubscriberLimits::default());
+        let peer = PeerId::random();
+        assert_eq!(
+            status(
+                &mut subscribers,
+                peer,
+                FilterSubscribeType::SubscriberPing,
+                &[]
+            ),
+            404
+        );
+        let response = subscribers.handle(
+            peer,
+            request(
+                FilterSubscribeType::Subscribe,
+                &["/toychat/2/huilong/proto"],
+            ),
+        );
+        assert_eq!(response.request_id, "request");
+        assert_eq!(response.status_code, 200);
+        assert_eq!(
+            status(
+                &mut subscribers,
+                peer,
+                FilterSubscribeType::SubscriberPing,
+                &[]
+            ),
+            200
+        );
+        assert_eq!(
+            status(
+                &mut subscribers,
+                peer,
+                FilterSubscribeType::Unsubscribe,
+                &["/toychat/2/other/proto"]
+            ),
+            404
+        );
+        assert_eq!(
+            status(
+                &mut subscribers,
+                peer,
+                FilterSubscribeType::Unsubscribe,
+                &["/toychat/2/huilong/proto"]
+            ),
+            200
+        );
+        assert_eq!(
+            status(
+                &mut subscribers,
+                peer,
+                FilterSubscribeType::UnsubscribeAll,
+                &[]
+            ),
+            404
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let mut subscribers = Subscribers::new(SubscriberLimits::default());
+        let peer = PeerId::random();
+        assert_eq!(
+            status(&mut subscribers, peer, FilterSubscribeType::Subscribe, &[]),
+            400
+        );
+        let mut without_pubsub_topic = request(
+            FilterSubscribeType::Subscribe,
+            &["/toychat/2/huilong/proto"],
+        );
+        without_pubsub_topic.pubsub_topic = None;
+        assert_eq!(
+            subscribers.handle(peer, without_pubsub_topic).status_code,
+            400
+        );
+        let content_topics = vec!["/toychat/2/huilong/proto"; MAX_CONTENT_TOPICS_PER_REQUEST + 1];
+        assert_eq!(
+            status(
+                &mut subscribers,
+                peer,
+                FilterSubscribeType::Subscribe,
+                &content_topics
+            ),
+            400
+        );
+        let mut unknown = request(FilterSubscribeType::SubscriberPing, &[]);
+        unknown.filter_subscribe_type = 4;
+        assert_eq!(subscribers.handle(peer, unknown).status_code, 400);
+    }
+
+    #[test]
+    fn limits_subscribers() {
+        let mut subscribers = Subscribers::new(limits(1, 10));
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let topics = ["/toychat/2/huilong/proto"];
+        assert_eq!(
+            status(
+                &mut subscribers,
+                first,
+                FilterSubscribeType::Subscribe,
+                &topics
+            ),
+            200
+        );
+        assert_eq!(
+            status(
+                &mut subscribers,
+                second,
+                FilterSubscribeType::Subscribe,
+                &topics
+            ),
+            503
+        );
+        // Existing subscribers may still add criteria
+        assert_eq!(
+            status(
+                &mut subscribers,
+                first,
+                FilterSubscribeType::Subscribe,
+                &["/toychat/2/other/proto"]
+            ),
+            200
+        );
+    }
+
+    #[test]
+    fn limits_criteria() {
+        let mut subscribers = Subscribers::new(limits(10, 2));
+        let peer = PeerId::random();
+        let topics = ["/toychat/2/a/proto", "/toychat/2/b/proto"];
+        assert_eq!(
+            status(
+                &mut subscribers,
+                peer,
+                FilterSubscribeType::Subscribe,
+                &topics
+            ),
+            200
+        );
+        // Criteria already held don't count twice
+        assert_eq!(
+            status(
+                &mut subscribers,
+                peer,
+                FilterSubscribeType::Subscribe,
+                &topics
+            ),
+            200
+        );
+        assert_eq!(
+            status(
+                &mut subscribers,
+                peer,
+                FilterSubscribeType::Subscribe,
+                &["/toychat/2/c/proto"]
+            ),
+            503
+        );
+        assert_eq!(
+            subscribers.matching(PUBSUB_TOPIC, "/toychat/2/c/proto"),
+            vec![]
+        );
+
+        // A new peer exceeding the limit right away isn't kept as a subscriber
+        let mut subscribers = Subscribers::new(limits(1, 1));
+        assert_eq!(
+            status(
+                &mut subscribers,
+                peer,
+                FilterSubscribeType::Subscribe,
+                &topics
+            ),
+            503
+        );
+        assert_eq!(
+            status(
+                &mut subscribers,
+                PeerId::random(),
+                FilterSubscribeType::Subscribe,
+                &topics[..1]
+            ),
+            200
+        );
+    }
+
+    #[test]
+    fn expires_quiet_subscribers() {
+        let mut subscribers = Subscribers::new(SubscriberLimits {
+            timeout: Duration::ZERO,
+            ..Default::default()
+        });
+        let peer = PeerId::random();
+        let topics = ["/toychat/2/huilong/proto"];
+        assert_eq!(
+            status(
+                &mut subscribers,
+                peer,
+                FilterSubscribeType::Subscribe,
+                &topics
+            ),
+            200
+        );
+        assert_eq!(subscribers.matching(PUBSUB_TOPIC, topics[0]), vec![]);
+        assert_eq!(
+            status(
+                &mut subscribers,
+                peer,
+                FilterSubscribeType::SubscriberPing,
+                &[]
+            ),
+            404
+        );
+    }
+
+    #[test]
+    fn matches_subscribers_by_pubsub_and_content_topic() {
+        let mut subscribers = Subscribers::new(SubscriberLimits::default());
+        let (first, second) = (PeerId::random(), PeerId::random());
+        status(
+            &mut subscribers,
+            first,
+            FilterSubscribeType::Subscribe,
+            &["/toychat/2/a/proto", "/toychat/2/b/proto"],
+        );
+        status(
+            &mut subscribers,
+            second,
+            FilterSubscribeType::Subscribe,
+            &["/toychat/2/b/proto"],
+        );
+        assert_eq!(
+            subscribers.matching(PUBSUB_TOPIC, "/toychat/2/a/proto"),
+            vec![first]
+        );
+        let mut matching = subscribers.matching(PUBSUB_TOPIC, "/toychat/2/b/proto");
+        matching.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(matching, expected);
+        assert_eq!(
+            subscribers.matching("/waku/2/rs/1/1", "/toychat/2/b/proto"),
+            vec![]
+        );
+    }
+}
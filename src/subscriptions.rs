@@ -0,0 +1,650 @@
+//! Filter subscriptions the node keeps alive with service nodes
+use libp2p::{request_response::OutboundRequestId, PeerId};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::{
+    filter::{self, filter_subscribe_request::FilterSubscribeType, FilterSubscribeResponse},
+    ContentTopic, PubsubTopic,
+};
+
+/// Content topics by pubsub topic
+type Topics = BTreeMap<PubsubTopic, BTreeSet<ContentTopic>>;
+
+/// An active filter subscription
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subscription {
+    /// The service node holding the subscription
+    pub peer: PeerId,
+    pub pubsub_topic: PubsubTopic,
+    pub content_topics: Vec<ContentTopic>,
+}
+
+/// A filter request, as far as subscription tracking is concerned
+///
+/// Internal requests are the ones the node sends on its own, to keep subscriptions
+/// redundant and alive.
+#[derive(Clone, Debug)]
+pub(crate) enum FilterRequest {
+    Ping,
+    Subscribe {
+        pubsub_topic: PubsubTopic,
+        content_topics: Vec<ContentTopic>,
+        internal: bool,
+    },
+    Unsubscribe {
+        pubsub_topic: PubsubTopic,
+        content_topics: Vec<ContentTopic>,
+        internal: bool,
+    },
+    UnsubscribeAll,
+}
+
+impl FilterRequest {
+    pub fn into_rpc(self, request_id: String) -> filter::FilterSubscribeRequest {
+        let (filter_subscribe_type, pubsub_topic, content_topics) = match self {
+            Self::Ping => (FilterSubscribeType::SubscriberPing, None, vec![]),
+            Self::Subscribe {
+                pubsub_topic,
+                content_topics,
+                ..
+            } => (
+                FilterSubscribeType::Subscribe,
+                Some(pubsub_topic),
+                content_topics,
+            ),
+            Self::Unsubscribe {
+                pubsub_topic,
+                content_topics,
+                ..
+            } => (
+                FilterSubscribeType::Unsubscribe,
+                Some(pubsub_topic),
+                content_topics,
+            ),
+            Self::UnsubscribeAll => (FilterSubscribeType::UnsubscribeAll, None, vec![]),
+        };
+        filter::FilterSubscribeRequest {
+            request_id,
+            filter_subscribe_type: filter_subscribe_type as i32,
+            pubsub_topic: pubsub_topic.as_ref().map(ToString::to_string),
+            content_topics: content_topics.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    /// Whether the node sent the request on its own, rather than on behalf of the application
+    pub fn is_internal(&self) -> bool {
+        match self {
+            Self::Ping => true,
+            Self::Subscribe { internal, .. } | Self::Unsubscribe { internal, .. } => *internal,
+            Self::UnsubscribeAll => false,
+        }
+    }
+}
+
+/// A filter request in flight
+struct SentRequest {
+    peer: PeerId,
+    /// Id the response has to echo
+    rpc_request_id: String,
+    request: FilterRequest,
+}
+
+/// What the outcome of a tracked filter request means for the node
+pub(crate) enum Outcome {
+    /// The application subscribed to new content topics, to be made redundant
+    Wanted,
+    /// A service node lost our subscriptions, reachable or not
+    PingFailed { unreachable: bool },
+    /// Lost subscriptions got held by a service node again
+    Restored {
+        pubsub_topic: PubsubTopic,
+        content_topics: Vec<ContentTopic>,
+    },
+    /// A service node holds subscriptions the application gave up on meanwhile
+    Unwanted {
+        pubsub_topic: PubsubTopic,
+        content_topics: Vec<ContentTopic>,
+    },
+}
+
+/// What the application subscribed to, and the service nodes holding it
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+    /// Subscriptions the application wants kept alive
+    wanted: Topics,
+    /// Subscriptions by the service node holding them
+    active: HashMap<PeerId, Topics>,
+    /// Wanted subscriptions that lost a service node, until held by a new one
+    lost: Topics,
+    /// Service nodes which ever accepted a subscription
+    service_nodes: BTreeSet<PeerId>,
+    /// Filter requests in flight, applied once successful
+    requests: HashMap<OutboundRequestId, SentRequest>,
+}
+
+impl Subscriptions {
+    /// Active subscriptions, by service node and pubsub topic
+    pub fn list(&self) -> Vec<Subscription> {
+        self.active
+            .iter()
+            .flat_map(|(peer, topics)| {
+                topics
+                    .iter()
+                    .map(|(pubsub_topic, content_topics)| Subscription {
+                        peer: *peer,
+                        pubsub_topic: *pubsub_topic,
+                        content_topics: content_topics.iter().cloned().collect(),
+                    })
+            })
+            .collect()
+    }
+
+    /// Service nodes holding subscriptions of ours
+    pub fn peers(&self) -> Vec<PeerId> {
+        self.active.keys().copied().collect()
+    }
+
+    /// Service nodes which ever accepted a subscription of ours
+    pub fn service_nodes(&self) -> impl Iterator<Item = &PeerId> {
+        self.service_nodes.iter()
+    }
+
+    /// Subscriptions a service node holds
+    pub fn held_by(&self, peer: &PeerId) -> Vec<(PubsubTopic, Vec<ContentTopic>)> {
+        self.active
+            .get(peer)
+            .into_iter()
+            .flatten()
+            .map(|(pubsub_topic, content_topics)| {
+                (*pubsub_topic, content_topics.iter().cloned().collect())
+            })
+            .collect()
+    }
+
+    /// Whether a service node holds a subscription, or is about to, its pushes on the
+    /// topics being expected then
+    pub fn expects(
+        &self,
+        peer: &PeerId,
+        pubsub_topic: &PubsubTopic,
+        content_topic: &ContentTopic,
+    ) -> bool {
+        self.holders(pubsub_topic, content_topic).contains(peer)
+    }
+
+    /// Service nodes other than `peer` holding any of the given subscriptions, with those they hold
+    pub fn held_elsewhere(
+        &self,
+        peer: &PeerId,
+        pubsub_topic: &PubsubTopic,
+        content_topics: &[ContentTopic],
+    ) -> Vec<(PeerId, Vec<ContentTopic>)> {
+        self.active
+            .iter()
+            .filter(|(holder, _)| *holder != peer)
+            .filter_map(|(holder, topics)| {
+                let held: Vec<_> = content_topics
+                    .iter()
+                    .filter(|content_topic| {
+                        topics
+                            .get(pubsub_topic)
+                            .is_some_and(|subscribed| subscribed.contains(*content_topic))
+                    })
+                    .cloned()
+                    .collect();
+                (!held.is_empty()).then_some((*holder, held))
+            })
+            .collect()
+    }
+
+    /// Track a sent filter request, to apply it once the service node accepts it
+    ///
+    /// The application unsubscribing stops us from keeping subscriptions alive right away,
+    /// whatever the service node says.
+    pub fn sent(
+        &mut self,
+        request_id: OutboundRequestId,
+        peer: PeerId,
+        rpc_request_id: String,
+        request: FilterRequest,
+    ) {
+        match &request {
+            FilterRequest::Unsubscribe {
+                pubsub_topic,
+                content_topics,
+                internal: false,
+            } => {
+                remove_topics(&mut self.wanted, pubsub_topic, content_topics);
+                remove_topics(&mut self.lost, pubsub_topic, content_topics);
+            }
+            FilterRequest::UnsubscribeAll => {
+                for (pubsub_topic, content_topics) in self.held_by(&peer) {
+                    remove_topics(&mut self.wanted, &pubsub_topic, &content_topics);
+                    remove_topics(&mut self.lost, &pubsub_topic, &content_topics);
+                }
+            }
+            _ => {}
+        }
+        self.requests.insert(
+            request_id,
+            SentRequest {
+                peer,
+                rpc_request_id,
+                request,
+            },
+        );
+    }
+
+    /// Apply the outcome of a tracked filter request, `None` meaning it failed
+    ///
+    /// Returns the request along with what its outcome means, if it is a tracked one.
+    pub fn handle(
+        &mut self,
+        request_id: &OutboundRequestId,
+        response: Option<&FilterSubscribeResponse>,
+    ) -> Option<(FilterRequest, Vec<Outcome>)> {
+        let sent = self.requests.remove(request_id)?;
+        let accepted = response.is_some_and(|response| {
+            response.request_id == sent.rpc_request_id && response.status_code == filter::STATUS_OK
+        });
+        let outcomes = match (sent.request.clone(), accepted) {
+            (FilterRequest::Ping, false) => vec![Outcome::PingFailed {
+                unreachable: response.is_none(),
+            }],
+            (
+                FilterRequest::Subscribe {
+                    pubsub_topic,
+                    content_topics,
+                    internal,
+                },
+                true,
+            ) => self.subscribed(sent.peer, pubsub_topic, content_topics, internal),
+            (
+                FilterRequest::Unsubscribe {
+                    pubsub_topic,
+                    content_topics,
+                    ..
+                },
+                true,
+            ) => {
+                if let Some(topics) = self.active.get_mut(&sent.peer) {
+                    remove_topics(topics, &pubsub_topic, &content_topics);
+                    if topics.is_empty() {
+                        self.active.remove(&sent.peer);
+                    }
+                }
+                vec![]
+            }
+            (FilterRequest::UnsubscribeAll, true) => {
+                self.active.remove(&sent.peer);
+                vec![]
+            }
+            // Failed subscriptions get topped up again on the next ping
+            _ => vec![],
+        };
+        Some((sent.request, outcomes))
+    }
+
+    /// Record subscriptions a service node accepted
+    fn subscribed(
+        &mut self,
+        peer: PeerId,
+        pubsub_topic: PubsubTopic,
+        content_topics: Vec<ContentTopic>,
+        internal: bool,
+    ) -> Vec<Outcome> {
+        let mut outcomes = vec![];
+        if !internal {
+            self.wanted
+                .entry(pubsub_topic)
+                .or_default()
+                .extend(content_topics.iter().cloned());
+            outcomes.push(Outcome::Wanted);
+        }
+        let (wanted, unwanted): (Vec<_>, Vec<_>) =
+            content_topics.into_iter().partition(|content_topic| {
+                self.wanted
+                    .get(&pubsub_topic)
+                    .is_some_and(|wanted| wanted.contains(content_topic))
+            });
+        if !unwanted.is_empty() {
+            outcomes.push(Outcome::Unwanted {
+                pubsub_topic,
+                content_topics: unwanted,
+            });
+        }
+        if wanted.is_empty() {
+            return outcomes;
+        }
+        self.service_nodes.insert(peer);
+        self.active
+            .entry(peer)
+            .or_default()
+            .entry(pubsub_topic)
+            .or_default()
+            .extend(wanted.iter().cloned());
+        let restored: Vec<_> = wanted
+            .into_iter()
+            .filter(|content_topic| {
+                self.lost
+                    .get(&pubsub_topic)
+                    .is_some_and(|lost| lost.contains(content_topic))
+            })
+            .collect();
+        if !restored.is_empty() {
+            remove_topics(&mut self.lost, &pubsub_topic, &restored);
+            outcomes.push(Outcome::Restored {
+                pubsub_topic,
+                content_topics: restored,
+            });
+        }
+        outcomes
+    }
+
+    /// Mark the subscriptions held by a service node as lost
+    ///
+    /// Returns the lost subscriptions.
+    pub fn lose(&mut self, peer: &PeerId) -> Vec<(PubsubTopic, Vec<ContentTopic>)> {
+        let topics = self.active.remove(peer).unwrap_or_default();
+        topics
+            .into_iter()
+            .map(|(pubsub_topic, content_topics)| {
+                let wanted = self.wanted.get(&pubsub_topic);
+                self.lost.entry(pubsub_topic).or_default().extend(
+                    content_topics
+                        .iter()
+                        .filter(|content_topic| {
+                            wanted.is_some_and(|wanted| wanted.contains(*content_topic))
+                        })
+                        .cloned(),
+                );
+                (pubsub_topic, content_topics.into_iter().collect())
+            })
+            .collect()
+    }
+
+    /// Subscriptions to add for every wanted content topic to be held by `redundancy`
+    /// distinct service nodes, picked from `candidates` in order
+    pub fn top_ups(
+        &self,
+        redundancy: usize,
+        candidates: &[PeerId],
+    ) -> BTreeMap<(PeerId, PubsubTopic), Vec<ContentTopic>> {
+        let mut top_ups: BTreeMap<(PeerId, PubsubTopic), Vec<ContentTopic>> = BTreeMap::new();
+        for (pubsub_topic, content_topics) in &self.wanted {
+            for content_topic in content_topics {
+                let holders = self.holders(pubsub_topic, content_topic);
+                let missing = redundancy.saturating_sub(holders.len());
+                for peer in candidates
+                    .iter()
+                    .filter(|peer| !holders.contains(peer))
+                    .take(missing)
+                {
+                    top_ups
+                        .entry((*peer, *pubsub_topic))
+                        .or_default()
+                        .push(content_topic.clone());
+                }
+            }
+        }
+        top_ups
+    }
+
+    /// Service nodes holding a subscription, or about to
+    fn holders(&self, pubsub_topic: &PubsubTopic, content_topic: &ContentTopic) -> HashSet<PeerId> {
+        let active = self.active.iter().filter(|(_, topics)| {
+            topics
+                .get(pubsub_topic)
+                .is_some_and(|subscribed| subscribed.contains(content_topic))
+        });
+        let requested = self.requests.values().filter(|sent| {
+            matches!(
+                &sent.request,
+                FilterRequest::Subscribe {
+                    pubsub_topic: requested,
+                    content_topics,
+                    ..
+                } if requested == pubsub_topic && content_topics.contains(content_topic)
+            )
+        });
+        active
+            .map(|(peer, _)| *peer)
+            .chain(requested.map(|sent| sent.peer))
+            .collect()
+    }
+}
+
+fn remove_topics(topics: &mut Topics, pubsub_topic: &PubsubTopic, content_topics: &[ContentTopic]) {
+    if let Some(subscribed) = topics.get_mut(pubsub_topic) {
+        for content_topic in content_topics {
+            subscribed.remove(content_topic);
+        }
+        if subscribed.is_empty() {
+            topics.remove(pubsub_topic);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{request_response, StreamProtocol};
+
+    use super::*;
+    use crate::FilterError;
+
+    const CLUSTER_ID: u16 = 1;
+
+    /// Subscriptions along with a filter behaviour, minting the ids of sent requests
+    struct Harness {
+        filter: request_response::Behaviour<filter::Codec>,
+        subscriptions: Subscriptions,
+        peers: Vec<PeerId>,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            Self {
+                filter: request_response::Behaviour::with_codec(
+                    filter::codec(),
+                    [(
+                        StreamProtocol::new(filter::PROTOCOL_NAME),
+                        request_response::ProtocolSupport::Outbound,
+                    )],
+                    request_response::Config::default(),
+                ),
+                subscriptions: Subscriptions::default(),
+                peers: (0..4).map(|_| PeerId::random()).collect(),
+            }
+        }
+
+        fn send(&mut self, peer: PeerId, request: FilterRequest) -> OutboundRequestId {
+            let rpc_request_id = format!("request-{}", self.subscriptions.requests.len());
+            let request_id = self
+                .filter
+                .send_request(&peer, request.clone().into_rpc(rpc_request_id.clone()));
+            self.subscriptions
+                .sent(request_id, peer, rpc_request_id, request);
+            request_id
+        }
+
+        fn accept(&mut self, request_id: OutboundRequestId) -> Vec<Outcome> {
+            let rpc_request_id = self.subscriptions.requests[&request_id]
+                .rpc_request_id
+                .clone();
+            let response = FilterSubscribeResponse::new(rpc_request_id, Ok(()));
+            let (_, outcomes) = self
+                .subscriptions
+                .handle(&request_id, Some(&response))
+                .unwrap();
+            outcomes
+        }
+
+        fn subscribe(&mut self, peer: PeerId, content_topics: &[&ContentTopic], internal: bool) {
+            let request_id = self.send(peer, subscribe(content_topics, internal));
+            self.accept(request_id);
+        }
+    }
+
+    fn pubsub_topic() -> PubsubTopic {
+        PubsubTopic::new(CLUSTER_ID, 0)
+    }
+
+    fn content_topic(name: &str) -> ContentTopic {
+        ContentTopic::new("toychat", "2", name, "proto").unwrap()
+    }
+
+    fn subscribe(content_topics: &[&ContentTopic], internal: bool) -> FilterRequest {
+        FilterRequest::Subscribe {
+            pubsub_topic: pubsub_topic(),
+            content_topics: content_topics.iter().copied().cloned().collect(),
+            internal,
+        }
+    }
+
+    fn unsubscribe(content_topics: &[&ContentTopic]) -> FilterRequest {
+        FilterRequest::Unsubscribe {
+            pubsub_topic: pubsub_topic(),
+            content_topics: content_topics.iter().copied().cloned().collect(),
+            internal: false,
+        }
+    }
+
+    #[test]
+    fn tops_up_to_redundancy() {
+        let mut harness = Harness::new();
+        let [a, b, c, d] = harness.peers[..] else {
+            unreachable!()
+        };
+        let topic = content_topic("huilong");
+        harness.subscribe(a, &[&topic], false);
+
+        let top_ups = harness.subscriptions.top_ups(3, &[a, b, c, d]);
+        assert_eq!(
+            top_ups,
+            BTreeMap::from([
+                ((b, pubsub_topic()), vec![topic.clone()]),
+                ((c, pubsub_topic()), vec![topic.clone()]),
+            ])
+        );
+
+        // A subscription in flight counts, not to be requested twice
+        harness.send(b, subscribe(&[&topic], true));
+        let top_ups = harness.subscriptions.top_ups(3, &[a, b, c, d]);
+        assert_eq!(
+            top_ups,
+            BTreeMap::from([((c, pubsub_topic()), vec![topic.clone()])])
+        );
+
+        harness.subscribe(c, &[&topic], true);
+        assert!(harness.subscriptions.top_ups(3, &[a, b, c, d]).is_empty());
+    }
+
+    #[test]
+    fn replaces_lost_subscriptions() {
+        let mut harness = Harness::new();
+        let [a, b, c, _] = harness.peers[..] else {
+            unreachable!()
+        };
+        let topic = content_topic("huilong");
+        harness.subscribe(a, &[&topic], false);
+        harness.subscribe(b, &[&topic], true);
+
+        assert_eq!(
+            harness.subscriptions.lose(&a),
+            vec![(pubsub_topic(), vec![topic.clone()])]
+        );
+        assert_eq!(harness.subscriptions.peers(), vec![b]);
+        assert!(harness.subscriptions.held_by(&a).is_empty());
+
+        let top_ups = harness.subscriptions.top_ups(2, &[b, c]);
+        assert_eq!(
+            top_ups.get(&(c, pubsub_topic())),
+            Some(&vec![topic.clone()])
+        );
+        assert_eq!(top_ups.len(), 1);
+
+        let request_id = harness.send(c, subscribe(&[&topic], true));
+        let outcomes = harness.accept(request_id);
+        assert!(matches!(
+            &outcomes[..],
+            [Outcome::Restored { pubsub_topic: restored, content_topics }]
+                if *restored == pubsub_topic() && *content_topics == vec![topic.clone()]
+        ));
+        assert!(harness.subscriptions.top_ups(2, &[a, b, c]).is_empty());
+    }
+
+    #[test]
+    fn gives_up_on_subscriptions_unsubscribed_while_in_flight() {
+        let mut harness = Harness::new();
+        let [a, b, c, _] = harness.peers[..] else {
+            unreachable!()
+        };
+        let kept = content_topic("kept");
+        let dropped = content_topic("dropped");
+        harness.subscribe(a, &[&kept, &dropped], false);
+        harness.subscribe(b, &[&kept, &dropped], true);
+        harness.subscriptions.lose(&b);
+
+        // Replacing the lost subscriptions, while the application unsubscribes from one
+        let request_id = harness.send(c, subscribe(&[&kept, &dropped], true));
+        harness.send(a, unsubscribe(&[&dropped]));
+        assert!(harness
+            .subscriptions
+            .top_ups(2, &[a, b, c])
+            .values()
+            .all(|content_topics| !content_topics.contains(&dropped)));
+
+        let outcomes = harness.accept(request_id);
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes.iter().any(|outcome| matches!(
+            outcome,
+            Outcome::Unwanted { content_topics, .. } if *content_topics == vec![dropped.clone()]
+        )));
+        assert!(outcomes.iter().any(|outcome| matches!(
+            outcome,
+            Outcome::Restored { content_topics, .. } if *content_topics == vec![kept.clone()]
+        )));
+        assert_eq!(
+            harness.subscriptions.held_by(&c),
+            vec![(pubsub_topic(), vec![kept.clone()])]
+        );
+    }
+
+    #[test]
+    fn expects_pushes_of_holders_only() {
+        let mut harness = Harness::new();
+        let [a, b, c, _] = harness.peers[..] else {
+            unreachable!()
+        };
+        let (topic, other) = (content_topic("huilong"), content_topic("other"));
+        harness.subscribe(a, &[&topic], false);
+        harness.send(b, subscribe(&[&topic], true));
+        let expects = |peer, pubsub_topic, content_topic| {
+            harness
+                .subscriptions
+                .expects(&peer, &pubsub_topic, content_topic)
+        };
+        assert!(expects(a, pubsub_topic(), &topic));
+        assert!(expects(b, pubsub_topic(), &topic));
+        assert!(!expects(c, pubsub_topic(), &topic));
+        assert!(!expects(a, pubsub_topic(), &other));
+        assert!(!expects(a, PubsubTopic::new(CLUSTER_ID, 1), &topic));
+    }
+
+    #[test]
+    fn ignores_rejected_subscriptions() {
+        let mut harness = Harness::new();
+        let a = harness.peers[0];
+        let topic = content_topic("huilong");
+        let request_id = harness.send(a, subscribe(&[&topic], false));
+        let response =
+            FilterSubscribeResponse::new(String::new(), Err(FilterError::NotFound(String::new())));
+        let (_, outcomes) = harness
+            .subscriptions
+            .handle(&request_id, Some(&response))
+            .unwrap();
+        assert!(outcomes.is_empty());
+        assert!(harness.subscriptions.list().is_empty());
+        assert!(harness.subscriptions.top_ups(1, &[a]).is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+//! Pubsub topics of statically sharded networks and content topics
+use std::{fmt, str::FromStr};
+
+use crate::Error;
+
+const STATIC_SHARDING_PREFIX: &str = "/waku/2/rs/";
+
+/// A pubsub topic of a statically sharded network: `/waku/2/rs/<cluster>/<shard>`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PubsubTopic {
+    pub cluster_id: u16,
+    pub shard: u16,
+}
+
+impl PubsubTopic {
+    pub fn new(cluster_id: u16, shard: u16) -> Self {
+        Self { cluster_id, shard }
+    }
+}
+
+impl fmt::Display for PubsubTopic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}/{}",
+            STATIC_SHARDING_PREFIX, self.cluster_id, self.shard
+        )
+    }
+}
+
+impl FromStr for PubsubTopic {
+    type Err = Error;
+
+    fn from_str(topic: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidPubsubTopic(topic.to_string());
+        let (cluster_id, shard) = topic
+            .strip_prefix(STATIC_SHARDING_PREFIX)
+            .and_then(|rest| rest.split_once('/'))
+            .ok_or_else(invalid)?;
+        Ok(Self {
+            cluster_id: cluster_id.parse().map_err(|_| invalid())?,
+            shard: shard.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// A content topic: `/{application}/{version}/{name}/{encoding}`
+///
+/// It may be prefixed by a generation: `/{generation}/{application}/{version}/{name}/{encoding}`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ContentTopic {
+    pub generation: Option<u32>,
+    pub application: String,
+    pub version: String,
+    pub name: String,
+    pub encoding: String,
+}
+
+impl ContentTopic {
+    pub fn new(
+        application: &str,
+        version: &str,
+        name: &str,
+        encoding: &str,
+    ) -> Result<Self, Error> {
+        format!("/{application}/{version}/{name}/{encoding}").parse()
+    }
+}
+
+impl fmt::Display for ContentTopic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(generation) = self.generation {
+            write!(f, "/{generation}")?;
+        }
+        write!(
+            f,
+            "/{}/{}/{}/{}",
+            self.application, self.version, self.name, self.encoding
+        )
+    }
+}
+
+impl FromStr for ContentTopic {
+    type Err = Error;
+
+    fn from_str(topic: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidContentTopic(topic.to_string());
+        let parts: Vec<&str> = topic
+            .strip_prefix('/')
+            .ok_or_else(invalid)?
+            .split('/')
+            .collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            return Err(invalid());
+        }
+        let (generation, [application, version, name, encoding]) = match parts[..] {
+            [application, version, name, encoding] => {
+                (None, [application, version, name, encoding])
+            }
+            [generation, application, version, name, encoding] => (
+                Some(generation.parse().map_err(|_| invalid())?),
+                [application, version, name, encoding],
+            ),
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            generation,
+            application: application.to_string(),
+            version: version.to_string(),
+            name: name.to_string(),
+            encoding: encoding.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_content_topic() {
+        let topic: ContentTopic = "/toychat/2/huilong/proto".parse().unwrap();
+        assert_eq!(topic.generation, None);
+        assert_eq!(topic.application, "toychat");
+        assert_eq!(topic.version, "2");
+        assert_eq!(topic.name, "huilong");
+        assert_eq!(topic.encoding, "proto");
+    }
+
+    #[test]
+    fn parses_content_topic_with_generation() {
+        let topic: ContentTopic = "/0/toychat/2/huilong/proto".parse().unwrap();
+        assert_eq!(topic.generation, Some(0));
+        assert_eq!(topic.application, "toychat");
+        assert_eq!(topic.encoding, "proto");
+        assert!("/zero/toychat/2/huilong/proto"
+            .parse::<ContentTopic>()
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_content_topics() {
+        for topic in [
+            "",
+            "/",
+            "toychat/2/huilong/proto",
+            "/toychat/2/huilong",
+            "/toychat//huilong/proto",
+            "/toychat/2/huilong/proto/",
+            "/0/1/toychat/2/huilong/proto",
+        ] {
+            assert!(topic.parse::<ContentTopic>().is_err(), "{topic}");
+        }
+    }
+
+    #[test]
+    fn displays_content_topic_as_parsed() {
+        for topic in ["/toychat/2/huilong/proto", "/0/toychat/2/huilong/proto"] {
+            assert_eq!(topic.parse::<ContentTopic>().unwrap().to_string(), topic);
+        }
+    }
+
+    #[test]
+    fn round_trips_pubsub_topic() {
+        let topic: PubsubTopic = "/waku/2/rs/1/3".parse().unwrap();
+        assert_eq!(topic, PubsubTopic::new(1, 3));
+        assert_eq!(topic.to_string(), "/waku/2/rs/1/3");
+        assert!("/waku/2/default-waku/proto".parse::<PubsubTopic>().is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! Validation of received messages before they get relayed or handed to the application
+use std::{collections::BTreeMap, fmt, str::FromStr, time::Duration};
+
+use libp2p::gossipsub::MessageAcceptance;
+use prost::Message;
+
+use crate::{message::MAX_WAKU_MESSAGE_SIZE, ContentTopic, PubsubTopic, WakuMessage};
+
+/// Outcome of validating a message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Valid, to be relayed and handed to the application
+    Accept,
+    /// Invalid, penalizing the peer that relayed it
+    Reject(RejectReason),
+    /// Dropped without penalizing anyone
+    Ignore,
+}
+
+/// Why a message got rejected
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RejectReason {
+    /// Not a protobuf encoded Waku message
+    Undecodable,
+    /// Larger than the max Waku message size
+    TooLarge,
+    /// Timestamp too far off local time
+    TimestampDrift,
+    /// Content topic not of the `/{application}/{version}/{name}/{encoding}` format
+    InvalidContentTopic,
+    /// Published on a pubsub topic outside the shards the node is configured with
+    UnsupportedShard,
+    /// Rejected by an application validator, for the reason it gave
+    Application(String),
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Undecodable => write!(f, "undecodable"),
+            Self::TooLarge => write!(f, "too large"),
+            Self::TimestampDrift => write!(f, "timestamp drift"),
+            Self::InvalidContentTopic => write!(f, "invalid content topic"),
+            Self::UnsupportedShard => write!(f, "unsupported shard"),
+            Self::Application(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl From<&Verdict> for MessageAcceptance {
+    fn from(verdict: &Verdict) -> Self {
+        match verdict {
+            Verdict::Accept => Self::Accept,
+            Verdict::Reject(_) => Self::Reject,
+            Verdict::Ignore => Self::Ignore,
+        }
+    }
+}
+
+/// An application check of received messages, run after the built-in ones
+pub trait Validator: Send {
+    fn validate(&self, pubsub_topic: &PubsubTopic, message: &WakuMessage) -> Verdict;
+}
+
+impl<F> Validator for F
+where
+    F: Fn(&PubsubTopic, &WakuMessage) -> Verdict + Send,
+{
+    fn validate(&self, pubsub_topic: &PubsubTopic, message: &WakuMessage) -> Verdict {
+        self(pubsub_topic, message)
+    }
+}
+
+/// The validator chain, counting rejections by reason
+pub(crate) struct Validation {
+    max_timestamp_drift: Duration,
+    /// Pubsub topics of the configured shards
+    pubsub_topics: Vec<PubsubTopic>,
+    validators: Vec<Box<dyn Validator>>,
+    rejections: BTreeMap<RejectReason, u64>,
+}
+
+impl Validation {
+    pub fn new(
+        max_timestamp_drift: Duration,
+        pubsub_topics: Vec<PubsubTopic>,
+        validators: Vec<Box<dyn Validator>>,
+    ) -> Self {
+        Self {
+            max_timestamp_drift,
+            pubsub_topics,
+            validators,
+            rejections: BTreeMap::new(),
+        }
+    }
+
+    /// Decode and validate a message received on a pubsub topic
+    ///
+    /// Returns the message unless it couldn't be decoded.
+    pub fn validate_encoded(
+        &mut self,
+        pubsub_topic: &str,
+        data: &[u8],
+    ) -> (Verdict, Option<WakuMessage>) {
+        if data.len() > MAX_WAKU_MESSAGE_SIZE {
+            return (self.reject(RejectReason::TooLarge), None);
+        }
+        match WakuMessage::decode(data) {
+            Ok(message) => (self.validate(pubsub_topic, &message), Some(message)),
+            Err(_) => (self.reject(RejectReason::Undecodable), None),
+        }
+    }
+
+    /// Validate a message relayed to us on a pubsub topic, counting its rejection
+    pub fn validate(&mut self, pubsub_topic: &str, message: &WakuMessage) -> Verdict {
+        match self.check(pubsub_topic, message) {
+            Verdict::Reject(reason) => self.reject(reason),
+            verdict => verdict,
+        }
+    }
+
+    /// Number of rejected relayed messages by reason
+    pub fn rejections(&self) -> &BTreeMap<RejectReason, u64> {
+        &self.rejections
+    }
+
+    /// Validate a message without counting its rejection, for messages pushed to us
+    /// which the pusher hears about instead
+    pub fn check(&self, pubsub_topic: &str, message: &WakuMessage) -> Verdict {
+        if message.encoded_len() > MAX_WAKU_MESSAGE_SIZE {
+            return Verdict::Reject(RejectReason::TooLarge);
+        }
+        if message
+            .validate_timestamp(self.max_timestamp_drift)
+            .is_err()
+        {
+            return Verdict::Reject(RejectReason::TimestampDrift);
+        }
+        if ContentTopic::from_str(&message.content_topic).is_err() {
+            return Verdict::Reject(RejectReason::InvalidContentTopic);
+        }
+        let pubsub_topic = match PubsubTopic::from_str(pubsub_topic) {
+            Ok(pubsub_topic) if self.pubsub_topics.contains(&pubsub_topic) => pubsub_topic,
+            _ => return Verdict::Reject(RejectReason::UnsupportedShard),
+        };
+        self.validators
+            .iter()
+            .map(|validator| validator.validate(&pubsub_topic, message))
+            .find(|verdict| *verdict != Verdict::Accept)
+            .unwrap_or(Verdict::Accept)
+    }
+
+    fn reject(&mut self, reason: RejectReason) -> Verdict {
+        *self.rejections.entry(reason.clone()).or_default() += 1;
+        Verdict::Reject(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBSUB_TOPIC: &str = "/waku/2/rs/1/0";
+
+    fn validation() -> Validation {
+        Validation::new(
+            Duration::from_secs(20),
+            vec![PubsubTopic::new(1, 0)],
+            vec![],
+        )
+    }
+
+    fn message(content_topic: &str) -> WakuMessage {
+        WakuMessage {
+            content_topic: content_topic.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn counts_relayed_rejections_only() {
+        let mut validation = validation();
+        let invalid = message("not-a-content-topic");
+        assert_eq!(
+            validation.check(PUBSUB_TOPIC, &invalid),
+            Verdict::Reject(RejectReason::InvalidContentTopic)
+        );
+        assert!(validation.rejections().is_empty());
+
+        let data = invalid.encode_to_vec();
+        validation.validate_encoded(PUBSUB_TOPIC, &data);
+        validation.validate_encoded(PUBSUB_TOPIC, &[0xff]);
+        assert_eq!(
+            validation.rejections(),
+            &BTreeMap::from([
+                (RejectReason::InvalidContentTopic, 1),
+                (RejectReason::Undecodable, 1),
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_shard() {
+        let message = message("/toychat/2/huilong/proto");
+        assert_eq!(
+            validation().check("/waku/2/rs/1/1", &message),
+            Verdict::Reject(RejectReason::UnsupportedShard)
+        );
+    }
+}